@@ -0,0 +1,52 @@
+use once_cell::sync::OnceCell;
+
+use crate::model::Request;
+
+static POLICY: OnceCell<Policy> = OnceCell::new();
+
+/// How much faster requests opting into `adaptive_speed` are spoken while
+/// their `queue_key` has a backlog.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Pending jobs of the key at which speeding up starts.
+    pub threshold: usize,
+
+    /// Speed factor added per pending job from the threshold on.
+    pub step: f32,
+
+    /// Speed is never raised above this.
+    pub max_speed: f32,
+
+    /// Also divide the `pause_*` values by the speed factor.
+    pub shorten_pauses: bool,
+}
+
+pub fn init(policy: Policy) {
+    POLICY.get_or_init(|| policy);
+}
+
+/// Adjusts the prosody of `body` for `pending` jobs queued ahead of it.
+/// Returns the speed factor applied.
+pub fn apply(body: &mut Request, pending: usize) -> f32 {
+    let Some(policy) = POLICY.get() else {
+        return 1.0;
+    };
+
+    if pending < policy.threshold {
+        return 1.0;
+    }
+
+    let factor = 1.0 + policy.step * (pending - policy.threshold + 1) as f32;
+    let speed = (body.speed * factor).min(policy.max_speed).max(body.speed);
+    let factor = speed / body.speed;
+
+    body.speed = speed;
+
+    if policy.shorten_pauses {
+        body.pause_middle = (body.pause_middle as f32 / factor) as i32;
+        body.pause_long = (body.pause_long as f32 / factor) as i32;
+        body.pause_sentence = (body.pause_sentence as f32 / factor) as i32;
+    }
+
+    factor
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context, Result, bail};
+use once_cell::sync::OnceCell;
+use sha1::{Digest, Sha1};
+
+use crate::audio::{SAMPLE_RATE, samples_to_bytes, wav::Wav};
+
+static ASSETS: OnceCell<Assets> = OnceCell::new();
+
+struct Assets {
+    dir: PathBuf,
+    tracks: RwLock<HashMap<String, Track>>,
+}
+
+struct Track {
+    samples: Arc<Vec<i16>>,
+
+    /// Digest of the samples, so that cached mixes follow re-uploads.
+    digest: String,
+}
+
+impl Track {
+    fn new(samples: Vec<i16>) -> Self {
+        Self {
+            digest: format!("{:x}", Sha1::digest(samples_to_bytes(&samples))),
+            samples: Arc::new(samples),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid asset name {name:?}: use letters, digits, '-' and '_'");
+    }
+
+    Ok(())
+}
+
+fn assets() -> &'static Assets {
+    ASSETS.get().unwrap()
+}
+
+/// Loads every `*.wav` in `dir` (created if missing) as a background asset.
+pub fn init(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).context("Failed to create asset directory")?;
+
+    let mut tracks = HashMap::new();
+
+    for entry in std::fs::read_dir(dir).context("Failed to read asset directory")? {
+        let path = entry?.path();
+
+        if path.extension().is_none_or(|ext| ext != "wav") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let bytes = std::fs::read(&path).context(format!("Failed to read asset {name}"))?;
+        let wav = Wav::decode(&bytes).context(format!("Failed to decode asset {name}"))?;
+
+        tracks.insert(name, Track::new(wav.to_mono()));
+    }
+
+    tracing::info!("{} background assets loaded", tracks.len());
+
+    ASSETS.get_or_init(|| Assets {
+        dir: dir.to_path_buf(),
+        tracks: RwLock::new(tracks),
+    });
+
+    Ok(())
+}
+
+pub fn get(name: &str) -> Option<Arc<Vec<i16>>> {
+    assets()
+        .tracks
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|track| track.samples.clone())
+}
+
+/// Digest of the current content of an asset.
+pub fn digest(name: &str) -> Option<String> {
+    assets()
+        .tracks
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|track| track.digest.clone())
+}
+
+/// Asset names with their durations in seconds.
+pub fn list() -> Vec<(String, f32)> {
+    let mut list: Vec<_> = assets()
+        .tracks
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, track)| {
+            (
+                name.clone(),
+                track.samples.len() as f32 / SAMPLE_RATE as f32,
+            )
+        })
+        .collect();
+
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    list
+}
+
+/// Decodes an uploaded WAV file and stores it, converted to 44.1 kHz mono.
+pub fn put(name: &str, wav: &[u8]) -> Result<()> {
+    validate_name(name)?;
+
+    let samples = Wav::decode(wav)?.to_mono();
+
+    std::fs::write(
+        assets().dir.join(format!("{name}.wav")),
+        Wav::mono(samples.clone()).encode(),
+    )
+    .context(format!("Failed to write asset {name}"))?;
+
+    assets()
+        .tracks
+        .write()
+        .unwrap()
+        .insert(name.to_string(), Track::new(samples));
+
+    Ok(())
+}
+
+/// Returns whether the asset existed.
+pub fn remove(name: &str) -> Result<bool> {
+    validate_name(name)?;
+
+    if assets().tracks.write().unwrap().remove(name).is_none() {
+        return Ok(false);
+    }
+
+    std::fs::remove_file(assets().dir.join(format!("{name}.wav")))
+        .context(format!("Failed to remove asset {name}"))?;
+
+    Ok(true)
+}
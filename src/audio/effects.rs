@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{SAMPLE_RATE, ms_to_samples};
+
+static PRESETS: OnceCell<HashMap<String, Vec<Effect>>> = OnceCell::new();
+
+/// Bounds on request effects, each of which runs on the worker thread.
+const MAX_EFFECTS: usize = 16;
+const MAX_DELAY_MS: u32 = 2000;
+const MAX_BANDS: usize = 16;
+
+/// Longest echo tail appended to the clip.
+const MAX_ECHO_TAIL_MS: u32 = 10_000;
+
+fn default_room_size() -> f32 {
+    0.5
+}
+
+fn default_damping() -> f32 {
+    0.5
+}
+
+fn default_wet() -> f32 {
+    0.3
+}
+
+fn default_delay_ms() -> u32 {
+    250
+}
+
+fn default_feedback() -> f32 {
+    0.4
+}
+
+fn default_mix() -> f32 {
+    0.5
+}
+
+fn default_q() -> f32 {
+    1.0
+}
+
+fn default_threshold_db() -> f32 {
+    -1.0
+}
+
+fn default_release_ms() -> f32 {
+    50.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EqualizerBand {
+    pub frequency: f32,
+    pub gain_db: f32,
+
+    #[serde(default = "default_q")]
+    pub q: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    Reverb {
+        #[serde(default = "default_room_size")]
+        room_size: f32,
+
+        #[serde(default = "default_damping")]
+        damping: f32,
+
+        #[serde(default = "default_wet")]
+        wet: f32,
+    },
+    Echo {
+        #[serde(default = "default_delay_ms")]
+        delay_ms: u32,
+
+        #[serde(default = "default_feedback")]
+        feedback: f32,
+
+        #[serde(default = "default_mix")]
+        mix: f32,
+    },
+    Equalizer {
+        bands: Vec<EqualizerBand>,
+    },
+    BandPass {
+        low_hz: f32,
+        high_hz: f32,
+    },
+    Telephone,
+    Radio,
+    Limiter {
+        #[serde(default = "default_threshold_db")]
+        threshold_db: f32,
+
+        #[serde(default = "default_release_ms")]
+        release_ms: f32,
+    },
+}
+
+/// Loads the named effect chains from a JSON object such as
+/// `{ "robot": [{ "type": "echo", "delay_ms": 30 }] }`.
+pub fn init_presets(path: Option<&Path>) -> Result<()> {
+    let presets = match path {
+        Some(path) => {
+            let json = std::fs::read_to_string(path).context("Failed to read effect presets")?;
+            serde_json::from_str(&json).context("Failed to parse effect presets")?
+        }
+        None => HashMap::new(),
+    };
+
+    PRESETS.get_or_init(|| presets);
+
+    Ok(())
+}
+
+pub fn preset(name: &str) -> Option<&'static [Effect]> {
+    PRESETS.get()?.get(name).map(Vec::as_slice)
+}
+
+/// Checks that a chain from a request stays within the bounds.
+pub fn validate(chain: &[Effect]) -> Result<(), String> {
+    if chain.len() > MAX_EFFECTS {
+        return Err(format!("At most {MAX_EFFECTS} effects are allowed"));
+    }
+
+    for effect in chain {
+        match effect {
+            Effect::Echo { delay_ms, .. } if *delay_ms > MAX_DELAY_MS => {
+                return Err(format!("Echo delay is at most {MAX_DELAY_MS} ms"));
+            }
+            Effect::Equalizer { bands } if bands.len() > MAX_BANDS => {
+                return Err(format!("An equalizer has at most {MAX_BANDS} bands"));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// RBJ Audio EQ Cookbook biquad (transposed direct form II).
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn omega(frequency: f32) -> (f32, f32) {
+        let w0 = 2.0 * PI * frequency.clamp(1.0, SAMPLE_RATE as f32 / 2.0 - 1.0) / SAMPLE_RATE as f32;
+        (w0.cos(), w0.sin())
+    }
+
+    fn lowpass(frequency: f32, q: f32) -> Self {
+        let (cos, sin) = Self::omega(frequency);
+        let alpha = sin / (2.0 * q);
+        Self::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn highpass(frequency: f32, q: f32) -> Self {
+        let (cos, sin) = Self::omega(frequency);
+        let alpha = sin / (2.0 * q);
+        Self::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn peaking(frequency: f32, gain_db: f32, q: f32) -> Self {
+        let (cos, sin) = Self::omega(frequency);
+        let alpha = sin / (2.0 * q.max(0.01));
+        let a = 10f32.powf(gain_db / 40.0);
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+fn filter(samples: &mut [f32], mut filters: Vec<Biquad>) {
+    for s in samples.iter_mut() {
+        *s = filters.iter_mut().fold(*s, |x, f| f.process(x));
+    }
+}
+
+pub(in crate::audio) fn band_pass(samples: &mut [f32], low_hz: f32, high_hz: f32) {
+    // Two cascaded Butterworth sections per edge (24 dB/oct)
+    let q = std::f32::consts::FRAC_1_SQRT_2;
+    filter(
+        samples,
+        vec![
+            Biquad::highpass(low_hz, q),
+            Biquad::highpass(low_hz, q),
+            Biquad::lowpass(high_hz, q),
+            Biquad::lowpass(high_hz, q),
+        ],
+    );
+}
+
+fn saturate(samples: &mut [f32], drive: f32) {
+    let norm = drive.tanh();
+    for s in samples.iter_mut() {
+        *s = (*s * drive).tanh() / norm;
+    }
+}
+
+fn echo(samples: &mut Vec<f32>, delay_ms: u32, feedback: f32, mix: f32) {
+    let delay = ms_to_samples(delay_ms).max(1);
+    let feedback = feedback.clamp(0.0, 0.95);
+
+    // Let the repeats ring out until they decay below -60 dB
+    let repeats = if feedback > 0.0 {
+        (0.001f32.ln() / feedback.ln()).ceil() as usize
+    } else {
+        1
+    };
+    let tail = (delay * repeats).min(ms_to_samples(MAX_ECHO_TAIL_MS));
+    samples.resize(samples.len() + tail, 0.0);
+
+    let mut line = vec![0.0; delay];
+    for (i, s) in samples.iter_mut().enumerate() {
+        let delayed = line[i % delay];
+        line[i % delay] = *s + delayed * feedback;
+        *s += delayed * mix;
+    }
+}
+
+/// Freeverb style reverb: parallel damped comb filters followed by serial all-passes.
+fn reverb(samples: &mut Vec<f32>, room_size: f32, damping: f32, wet: f32) {
+    const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+
+    let feedback = 0.7 + 0.28 * room_size.clamp(0.0, 1.0);
+    let damping = damping.clamp(0.0, 1.0) * 0.4;
+    let wet = wet.clamp(0.0, 1.0);
+
+    let tail = (feedback.ln().recip() * 0.001f32.ln() * COMB_TUNING[7] as f32) as usize;
+    samples.resize(samples.len() + tail, 0.0);
+
+    let mut combs: Vec<(Vec<f32>, f32)> =
+        COMB_TUNING.iter().map(|n| (vec![0.0; *n], 0.0)).collect();
+    let mut allpasses: Vec<Vec<f32>> = ALLPASS_TUNING.iter().map(|n| vec![0.0; *n]).collect();
+
+    for (i, s) in samples.iter_mut().enumerate() {
+        let input = *s * 0.015;
+
+        let mut out = combs
+            .iter_mut()
+            .map(|(line, store)| {
+                let idx = i % line.len();
+                let y = line[idx];
+                *store = y * (1.0 - damping) + *store * damping;
+                line[idx] = input + *store * feedback;
+                y
+            })
+            .sum::<f32>();
+
+        for line in allpasses.iter_mut() {
+            let idx = i % line.len();
+            let buffered = line[idx];
+            line[idx] = out + buffered * 0.5;
+            out = buffered - out;
+        }
+
+        *s = *s * (1.0 - wet) + out * wet * 3.0;
+    }
+}
+
+fn limiter(samples: &mut [f32], threshold_db: f32, release_ms: f32) {
+    let threshold = 10f32.powf(threshold_db.min(0.0) / 20.0);
+    let release = (-1.0 / (release_ms.max(1.0) / 1000.0 * SAMPLE_RATE as f32)).exp();
+
+    let mut envelope = 0.0f32;
+    for s in samples.iter_mut() {
+        envelope = s.abs().max(envelope * release);
+
+        if envelope > threshold {
+            *s *= threshold / envelope;
+        }
+
+        // Soft knee for whatever slips through the envelope
+        *s = threshold * (*s / threshold).tanh();
+    }
+}
+
+impl Effect {
+    fn apply(&self, samples: &mut Vec<f32>) {
+        match self {
+            Effect::Reverb {
+                room_size,
+                damping,
+                wet,
+            } => reverb(samples, *room_size, *damping, *wet),
+            Effect::Echo {
+                delay_ms,
+                feedback,
+                mix,
+            } => echo(samples, *delay_ms, *feedback, *mix),
+            Effect::Equalizer { bands } => filter(
+                samples,
+                bands
+                    .iter()
+                    .map(|b| Biquad::peaking(b.frequency, b.gain_db, b.q))
+                    .collect(),
+            ),
+            Effect::BandPass { low_hz, high_hz } => band_pass(samples, *low_hz, *high_hz),
+            Effect::Telephone => {
+                band_pass(samples, 300.0, 3400.0);
+                saturate(samples, 1.5);
+            }
+            Effect::Radio => {
+                band_pass(samples, 500.0, 5000.0);
+                saturate(samples, 2.5);
+            }
+            Effect::Limiter {
+                threshold_db,
+                release_ms,
+            } => limiter(samples, *threshold_db, *release_ms),
+        }
+    }
+}
+
+/// Runs the effects over the samples in the declared order.
+pub fn apply<'a>(samples: &mut Vec<i16>, chain: impl IntoIterator<Item = &'a Effect>) {
+    let mut chain = chain.into_iter().peekable();
+    if chain.peek().is_none() {
+        return;
+    }
+
+    let mut buffer: Vec<f32> = samples.iter().map(|s| *s as f32 / 32768.0).collect();
+
+    for effect in chain {
+        effect.apply(&mut buffer);
+    }
+
+    *samples = buffer
+        .iter()
+        .map(|s| (s * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+        .collect();
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{SAMPLE_RATE, ms_to_samples};
+
+/// Voice level (dBFS) above which the background is ducked.
+const DUCKING_THRESHOLD_DB: f32 = -40.0;
+
+fn default_volume() -> f32 {
+    0.3
+}
+
+fn default_ducking_db() -> f32 {
+    -12.0
+}
+
+fn default_attack_ms() -> f32 {
+    50.0
+}
+
+fn default_release_ms() -> f32 {
+    400.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Background {
+    /// Name of an asset uploaded through `/api/admin/assets`.
+    pub asset: String,
+
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+
+    /// Attenuation applied to the background while the voice is speaking.
+    #[serde(default = "default_ducking_db")]
+    pub ducking_db: f32,
+
+    #[serde(default = "default_attack_ms")]
+    pub attack_ms: f32,
+
+    #[serde(default = "default_release_ms")]
+    pub release_ms: f32,
+
+    #[serde(default)]
+    pub fade_in_ms: u32,
+
+    #[serde(default)]
+    pub fade_out_ms: u32,
+}
+
+fn smoothing(ms: f32) -> f32 {
+    (-1.0 / (ms.max(1.0) / 1000.0 * SAMPLE_RATE as f32)).exp()
+}
+
+/// Mixes the looped `track` under `voice`, ducking it whenever the voice is
+/// audible. The result keeps the length of the voice.
+pub fn mix_background(voice: &mut [i16], track: &[i16], background: &Background) {
+    if track.is_empty() {
+        return;
+    }
+
+    let threshold = 10f32.powf(DUCKING_THRESHOLD_DB / 20.0) * 32768.0;
+    let ducked = 10f32.powf(background.ducking_db.min(0.0) / 20.0);
+    let attack = smoothing(background.attack_ms);
+    let release = smoothing(background.release_ms);
+    let fade_in = ms_to_samples(background.fade_in_ms);
+    let fade_out = ms_to_samples(background.fade_out_ms);
+
+    let len = voice.len();
+    let mut envelope = 0.0f32;
+    let mut gain = 1.0f32;
+
+    for (i, (v, b)) in voice.iter_mut().zip(track.iter().cycle()).enumerate() {
+        envelope = (*v as f32).abs().max(envelope * release);
+
+        let target = if envelope > threshold { ducked } else { 1.0 };
+        let coefficient = if target < gain { attack } else { release };
+        gain = target + (gain - target) * coefficient;
+
+        let mut fade = 1.0f32;
+        if i < fade_in {
+            fade = fade.min(i as f32 / fade_in as f32);
+        }
+        if len - i <= fade_out {
+            fade = fade.min((len - i) as f32 / fade_out as f32);
+        }
+
+        let mixed = *v as f32 + *b as f32 * background.volume * gain * fade;
+        *v = mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    }
+}
@@ -0,0 +1,23 @@
+pub mod effects;
+pub mod mix;
+pub mod silence;
+pub mod stretch;
+pub mod watermark;
+pub mod wav;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub fn ms_to_samples(ms: u32) -> usize {
+    (ms as u64 * SAMPLE_RATE as u64 / 1000) as usize
+}
+
+pub fn samples_from_bytes(bytes: &[u8]) -> Vec<i16> {
+    bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+pub fn samples_to_bytes(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
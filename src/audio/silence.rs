@@ -0,0 +1,39 @@
+use crate::audio::ms_to_samples;
+
+/// Samples kept in front of the first (and after the last) audible sample,
+/// so soft consonant attacks and breath releases are not clipped.
+const GUARD_MS: u32 = 5;
+
+fn db_to_amplitude(db: f32) -> i32 {
+    (10f32.powf(db / 20.0) * i16::MAX as f32) as i32
+}
+
+/// Removes the leading and trailing samples quieter than `threshold_db` (dBFS).
+/// Returns how many leading samples were removed.
+pub fn trim(samples: &mut Vec<i16>, threshold_db: f32) -> usize {
+    let threshold = db_to_amplitude(threshold_db);
+    let is_audible = |s: &i16| (*s as i32).abs() > threshold;
+
+    let Some(first) = samples.iter().position(is_audible) else {
+        let removed = samples.len();
+        samples.clear();
+        return removed;
+    };
+
+    let last = samples.iter().rposition(is_audible).unwrap();
+
+    let guard = ms_to_samples(GUARD_MS);
+    let start = first.saturating_sub(guard);
+    let end = (last + 1 + guard).min(samples.len());
+
+    samples.truncate(end);
+    samples.drain(..start);
+
+    start
+}
+
+/// Surrounds the samples with exactly `leading` and `trailing` samples of silence.
+pub fn pad(samples: &mut Vec<i16>, leading: usize, trailing: usize) {
+    samples.splice(0..0, std::iter::repeat_n(0, leading));
+    samples.resize(samples.len() + trailing, 0);
+}
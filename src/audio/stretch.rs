@@ -0,0 +1,168 @@
+use std::f32::consts::PI;
+
+/// Analysis/synthesis frame of about 23 ms, short enough to follow phonemes.
+const FRAME: usize = 1024;
+const SYNTHESIS_HOP: usize = FRAME / 2;
+
+/// How far (in samples) a frame may move from its nominal position to line up
+/// with the waveform already written.
+const TOLERANCE: usize = 256;
+
+/// Stride used when evaluating the cross-correlation, trading accuracy for speed.
+const CORRELATION_STRIDE: usize = 4;
+
+/// Half width (in zero crossings) of the windowed sinc kernel used for resampling.
+const SINC_ZEROS: isize = 8;
+
+fn hann(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos())
+        .collect()
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .step_by(CORRELATION_STRIDE)
+        .map(|(a, b)| a * b)
+        .sum()
+}
+
+/// WSOLA time-scale modification. `rate` above 1.0 makes the speech faster
+/// without touching its pitch.
+pub fn time_stretch(input: &[f32], rate: f32) -> Vec<f32> {
+    if (rate - 1.0).abs() < 1e-3 || input.is_empty() {
+        return input.to_vec();
+    }
+
+    let output_len = (input.len() as f32 / rate) as usize;
+    let analysis_hop = SYNTHESIS_HOP as f32 * rate;
+    let window = hann(FRAME);
+
+    let mut padded = vec![0.0; TOLERANCE];
+    padded.extend_from_slice(input);
+    padded.resize(padded.len() + 2 * FRAME + 2 * TOLERANCE, 0.0);
+
+    let mut output = vec![0.0; output_len + FRAME];
+    let mut norm = vec![0.0; output_len + FRAME];
+    let mut previous: Option<usize> = None;
+
+    for (k, out_pos) in (0..output_len).step_by(SYNTHESIS_HOP).enumerate() {
+        let nominal = TOLERANCE + (k as f32 * analysis_hop) as usize;
+
+        let offset = match previous {
+            None => nominal,
+            Some(previous) => {
+                // The frame that would naturally follow what was written last time
+                let natural = &padded[previous + SYNTHESIS_HOP..previous + SYNTHESIS_HOP + FRAME];
+
+                (nominal - TOLERANCE..=nominal + TOLERANCE)
+                    .map(|candidate| {
+                        let c = correlation(natural, &padded[candidate..candidate + FRAME]);
+                        (candidate, c)
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+                    .0
+            }
+        };
+
+        for (i, w) in window.iter().enumerate() {
+            output[out_pos + i] += padded[offset + i] * w;
+            norm[out_pos + i] += w;
+        }
+
+        previous = Some(offset);
+    }
+
+    output.truncate(output_len);
+
+    output
+        .iter()
+        .zip(norm)
+        .map(|(s, n)| if n > 1e-3 { s / n } else { *s })
+        .collect()
+}
+
+/// Windowed sinc resampler producing `input.len() / ratio` samples; a ratio
+/// above 1.0 shortens the signal (and raises its pitch when played back).
+pub fn resample(input: &[f32], ratio: f32) -> Vec<f32> {
+    if (ratio - 1.0).abs() < 1e-6 || input.is_empty() {
+        return input.to_vec();
+    }
+
+    // Lower the cutoff when decimating to keep aliasing out of the result
+    let cutoff = ratio.recip().min(1.0);
+    let half_width = (SINC_ZEROS as f32 / cutoff).ceil() as isize;
+    let output_len = (input.len() as f32 / ratio) as usize;
+
+    (0..output_len)
+        .map(|i| {
+            let center = i as f32 * ratio;
+            let base = center.floor() as isize;
+
+            (base - half_width + 1..=base + half_width)
+                .filter(|j| *j >= 0 && (*j as usize) < input.len())
+                .map(|j| {
+                    let x = (j as f32 - center) * cutoff;
+                    let sinc = if x.abs() < 1e-6 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    let lanczos = if x.abs() < SINC_ZEROS as f32 {
+                        let y = PI * x / SINC_ZEROS as f32;
+                        if y.abs() < 1e-6 { 1.0 } else { y.sin() / y }
+                    } else {
+                        0.0
+                    };
+                    input[j as usize] * sinc * lanczos * cutoff
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Range of the speed and pitch factors [`apply`] takes.
+const MIN_SPEED: f32 = 0.1;
+pub const MAX_SPEED: f32 = 10.0;
+const MIN_PITCH: f32 = 0.25;
+const MAX_PITCH: f32 = 4.0;
+
+/// Checks post-synthesis speed and pitch factors against the range.
+pub fn validate(speed: f32, pitch: f32) -> Result<(), String> {
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        return Err(format!(
+            "post_speed must be from {MIN_SPEED} to {MAX_SPEED}, not {speed}"
+        ));
+    }
+
+    if !(MIN_PITCH..=MAX_PITCH).contains(&pitch) {
+        return Err(format!(
+            "post_pitch must be from {MIN_PITCH} to {MAX_PITCH}, not {pitch}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Applies post-synthesis speed and pitch multipliers to the samples.
+pub fn apply(samples: &mut Vec<i16>, speed: f32, pitch: f32) {
+    let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    let pitch = pitch.clamp(MIN_PITCH, MAX_PITCH);
+
+    if (speed - 1.0).abs() < 1e-3 && (pitch - 1.0).abs() < 1e-3 {
+        return;
+    }
+
+    let buffer: Vec<f32> = samples.iter().map(|s| *s as f32).collect();
+
+    // Shift the pitch first, then stretch the (at most 4 times) shorter or
+    // longer result to the requested duration
+    let buffer = time_stretch(&resample(&buffer, pitch), speed / pitch);
+
+    *samples = buffer
+        .iter()
+        .map(|s| s.clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+        .collect();
+}
@@ -0,0 +1,350 @@
+//! Spread-spectrum watermark carrying the server ID and generation time.
+//!
+//! Every frame carries one bit of an 80-bit codeword (16-bit server ID, 32-bit
+//! UNIX time and a 32-bit tag derived from the key) by adding a key-derived
+//! band-limited noise pattern whose sign is the bit. Its level follows the
+//! frame loudness so it stays masked by the speech, and the part of the speech
+//! that already correlates with the pattern is cancelled at embedding time
+//! (improved spread spectrum). The detector searches the frame alignment and
+//! the codeword phase, so trimmed, delayed or re-encoded clips are still
+//! recognized: about four seconds of speech are enough for an untouched clip,
+//! and about eight for one that went through a lossy codec.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::OnceCell;
+use sha1::{Digest, Sha1};
+
+use crate::audio::effects::band_pass;
+
+const FRAME: usize = 2048;
+const RAMP: usize = 128;
+const CODEWORD_BITS: usize = 80;
+
+/// Band kept by lossy codecs at typical speech bitrates, yet above most of the
+/// speech energy that would otherwise drown the pattern.
+const BAND_LOW_HZ: f32 = 2000.0;
+const BAND_HIGH_HZ: f32 = 6000.0;
+
+/// Frames decoded at most; two codewords are plenty and bound the search cost.
+const MAX_FRAMES: usize = CODEWORD_BITS * 2;
+
+/// Samples at 44.1 kHz that [`detect`] looks at; the rest is ignored.
+pub const DETECT_SAMPLES: usize = FRAME * (MAX_FRAMES + 1);
+
+/// Minimum share of the correlation that must agree with the decoded codeword.
+const MIN_CONFIDENCE: f32 = 0.2;
+
+static WATERMARK: OnceCell<Watermark> = OnceCell::new();
+
+struct Watermark {
+    key: String,
+    server_id: Option<u16>,
+    strength: f32,
+    pattern: Vec<f32>,
+    /// The pattern as it looks after the detector's own band-pass filter.
+    reference: Vec<f32>,
+    whitening: u128,
+}
+
+#[derive(Debug, Clone)]
+pub struct Detection {
+    pub server_id: u16,
+    pub timestamp: u32,
+    pub confidence: f32,
+}
+
+/// xorshift64*, good enough for a noise pattern and reproducible everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+}
+
+fn unit_rms(mut signal: Vec<f32>) -> Vec<f32> {
+    let rms = (signal.iter().map(|s| s * s).sum::<f32>() / signal.len() as f32).sqrt();
+    signal.iter_mut().for_each(|s| *s /= rms);
+    signal
+}
+
+/// Sets up the watermark. Embedding only happens when `server_id` is given,
+/// detection works either way as long as the key matches.
+pub fn init(key: &str, server_id: Option<u16>, strength: f32) {
+    let digest = Sha1::digest(key.as_bytes());
+    let mut rng = Rng(u64::from_le_bytes(digest[0..8].try_into().unwrap()) | 1);
+    let whitening = u128::from_le_bytes(digest[4..20].try_into().unwrap()) >> 48;
+
+    // Filter a few frames of noise and keep the settled last one
+    let mut noise: Vec<f32> = (0..FRAME * 3)
+        .map(|_| if rng.next() >> 63 == 0 { 1.0 } else { -1.0 })
+        .collect();
+    band_pass(&mut noise, BAND_LOW_HZ, BAND_HIGH_HZ);
+    let pattern = unit_rms(noise.split_off(FRAME * 2));
+
+    let mut reference = pattern.repeat(3);
+    band_pass(&mut reference, BAND_LOW_HZ, BAND_HIGH_HZ);
+    let reference = unit_rms(reference.split_off(FRAME * 2));
+
+    WATERMARK.get_or_init(|| Watermark {
+        key: key.to_string(),
+        server_id,
+        strength,
+        pattern,
+        reference,
+        whitening,
+    });
+}
+
+/// Server ID and strength of the watermark embedded into new clips, if any.
+pub fn settings() -> Option<(u16, f32)> {
+    let watermark = WATERMARK.get()?;
+    Some((watermark.server_id?, watermark.strength))
+}
+
+impl Watermark {
+    fn codeword(&self, server_id: u16, timestamp: u32) -> u128 {
+        let mut hasher = Sha1::new();
+        hasher.update(self.key.as_bytes());
+        hasher.update(server_id.to_be_bytes());
+        hasher.update(timestamp.to_be_bytes());
+        let tag = u32::from_be_bytes(hasher.finalize()[0..4].try_into().unwrap());
+
+        let word = (server_id as u128) << 64 | (timestamp as u128) << 32 | tag as u128;
+        word ^ self.whitening
+    }
+
+    fn decode(&self, word: u128) -> Option<(u16, u32)> {
+        let plain = word ^ self.whitening;
+        let server_id = (plain >> 64) as u16;
+        let timestamp = (plain >> 32) as u32;
+
+        (self.codeword(server_id, timestamp) == word).then_some((server_id, timestamp))
+    }
+}
+
+fn symbol(word: u128, bit: usize) -> f32 {
+    if word >> (CODEWORD_BITS - 1 - bit) & 1 == 1 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+/// Embeds the configured server ID and the current time. Does nothing unless
+/// a server ID was configured.
+pub fn embed(samples: &mut [i16]) {
+    let Some(watermark) = WATERMARK.get() else {
+        return;
+    };
+
+    let Some(server_id) = watermark.server_id else {
+        return;
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+
+    let word = watermark.codeword(server_id, timestamp);
+
+    // What the detector would see of the host signal alone
+    let mut host: Vec<f32> = samples.iter().map(|s| *s as f32).collect();
+    band_pass(&mut host, BAND_LOW_HZ, BAND_HIGH_HZ);
+
+    let envelope: Vec<f32> = (0..FRAME)
+        .map(|i| {
+            // Fade at the frame edges so sign flips do not click
+            let edge = i.min(FRAME - 1 - i).min(RAMP) as f32 / RAMP as f32;
+            0.5 - 0.5 * (edge * std::f32::consts::PI).cos()
+        })
+        .collect();
+
+    let gain: f32 = envelope
+        .iter()
+        .zip(&watermark.reference)
+        .map(|(e, r)| e * r * r)
+        .sum();
+
+    for (f, frame) in samples.chunks_mut(FRAME).enumerate() {
+        let rms = (frame.iter().map(|s| (*s as f32).powi(2)).sum::<f32>() / frame.len() as f32)
+            .sqrt();
+        let target = rms * watermark.strength;
+
+        // Cancel the part of the host that already correlates with the
+        // pattern, bounded to keep the change inaudible
+        let interference = host[f * FRAME..]
+            .iter()
+            .zip(&watermark.reference)
+            .map(|(h, r)| h * r)
+            .sum::<f32>()
+            / gain;
+        let amplitude = target * symbol(word, f % CODEWORD_BITS)
+            - interference.clamp(-3.0 * target, 3.0 * target);
+
+        for (i, s) in frame.iter_mut().enumerate() {
+            let marked = *s as f32 + amplitude * envelope[i] * watermark.pattern[i];
+            *s = marked.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+/// Raw (not energy normalized) correlation, so that quiet frames, which carry
+/// an equally quiet watermark, weigh in proportionally less.
+fn correlation(segment: &[f32], reference: &[f32]) -> f32 {
+    segment.iter().zip(reference).map(|(a, b)| a * b).sum::<f32>() / FRAME as f32
+}
+
+/// Looks for a watermark made with the configured key in 44.1 kHz mono audio.
+pub fn detect(samples: &[i16]) -> Option<Detection> {
+    let watermark = WATERMARK.get()?;
+
+    let mut signal: Vec<f32> = samples
+        .iter()
+        .take(DETECT_SAMPLES)
+        .map(|s| *s as f32)
+        .collect();
+    band_pass(&mut signal, BAND_LOW_HZ, BAND_HIGH_HZ);
+
+    // Try every frame alignment and every codeword phase; the key-derived tag
+    // rules out the wrong ones
+    (0..FRAME.min(signal.len()))
+        .filter_map(|offset| {
+            let frames: Vec<f32> = signal[offset..]
+                .chunks_exact(FRAME)
+                .map(|segment| correlation(segment, &watermark.reference))
+                .collect();
+
+            let total: f32 = frames.iter().map(|c| c.abs()).sum();
+
+            if frames.len() < CODEWORD_BITS || total == 0.0 {
+                return None;
+            }
+
+            (0..CODEWORD_BITS)
+                .filter_map(|phase| {
+                    let mut soft = [0.0f32; CODEWORD_BITS];
+                    for (f, c) in frames.iter().enumerate() {
+                        soft[(f + phase) % CODEWORD_BITS] += c;
+                    }
+
+                    let word = soft
+                        .iter()
+                        .fold(0u128, |word, s| word << 1 | (*s > 0.0) as u128);
+
+                    let (server_id, timestamp) = watermark.decode(word)?;
+
+                    let agreement: f32 = frames
+                        .iter()
+                        .enumerate()
+                        .map(|(f, c)| c * symbol(word, (f + phase) % CODEWORD_BITS))
+                        .sum();
+
+                    Some(Detection {
+                        server_id,
+                        timestamp,
+                        confidence: agreement / total,
+                    })
+                })
+                .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        })
+        .filter(|d| d.confidence >= MIN_CONFIDENCE)
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{SAMPLE_RATE, stretch::resample};
+
+    const SERVER_ID: u16 = 0x1234;
+
+    /// Ten seconds of voiced, syllable-like sound: harmonics of a gliding
+    /// pitch plus a little noise, under a 4 Hz envelope.
+    fn speech() -> Vec<i16> {
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        let mut phase = 0.0f32;
+
+        (0..SAMPLE_RATE as usize * 10)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let f0 = 140.0 + 40.0 * (t * 0.7).sin();
+                phase += 2.0 * std::f32::consts::PI * f0 / SAMPLE_RATE as f32;
+
+                let voiced: f32 = (1..30).map(|h| (phase * h as f32).sin() / h as f32).sum();
+                let noise = (rng.next() >> 40) as f32 / (1u64 << 24) as f32 - 0.5;
+                let envelope = (t * 4.0 * std::f32::consts::PI).sin().abs();
+
+                ((voiced * 0.5 + noise * 0.2) * envelope * 8000.0) as i16
+            })
+            .collect()
+    }
+
+    fn marked() -> Vec<i16> {
+        init("test-key", Some(SERVER_ID), 0.03);
+
+        let mut samples = speech();
+        embed(&mut samples);
+        samples
+    }
+
+    fn assert_detected(samples: &[i16]) {
+        let detection = detect(samples).expect("watermark not detected");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+
+        assert_eq!(detection.server_id, SERVER_ID);
+        assert!(now - detection.timestamp < 60);
+    }
+
+    #[test]
+    fn detects_clean_clip() {
+        assert_detected(&marked());
+    }
+
+    #[test]
+    fn detects_trimmed_clip() {
+        assert_detected(&marked()[12345..]);
+    }
+
+    #[test]
+    fn detects_delayed_clip() {
+        let mut delayed = vec![0; 5000];
+        delayed.extend(marked());
+
+        assert_detected(&delayed);
+    }
+
+    #[test]
+    fn detects_band_limited_clip() {
+        let mut signal: Vec<f32> = marked().iter().map(|s| *s as f32).collect();
+        band_pass(&mut signal, 300.0, 7000.0);
+
+        let samples: Vec<_> = signal.iter().map(|s| *s as i16).collect();
+        assert_detected(&samples);
+    }
+
+    #[test]
+    fn detects_resampled_clip() {
+        let signal: Vec<f32> = marked().iter().map(|s| *s as f32).collect();
+        let ratio = SAMPLE_RATE as f32 / 22050.0;
+        let round_trip = resample(&resample(&signal, ratio), ratio.recip());
+
+        let samples: Vec<_> = round_trip.iter().map(|s| *s as i16).collect();
+        assert_detected(&samples);
+    }
+
+    #[test]
+    fn ignores_unmarked_audio() {
+        init("test-key", Some(SERVER_ID), 0.03);
+
+        assert!(detect(&speech()).is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+
+use crate::audio::{SAMPLE_RATE, samples_to_bytes, stretch::resample};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Sample rates accepted when decoding; anything else is not audio we made
+/// and would only blow up when resampled.
+const MIN_SAMPLE_RATE: u32 = 8000;
+const MAX_SAMPLE_RATE: u32 = 192000;
+
+/// LIST/INFO fields. Strings are written as NUL-terminated UTF-8.
+#[derive(Debug, Default, Clone)]
+pub struct Info {
+    /// `INAM`
+    pub name: Option<String>,
+    /// `IART`
+    pub artist: Option<String>,
+    /// `ICMT`
+    pub comment: Option<String>,
+    /// `ISFT`
+    pub software: Option<String>,
+    /// `ICRD`
+    pub creation_date: Option<String>,
+}
+
+/// A `cue ` point with its `labl` text, positioned in sample frames.
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub position: u32,
+    pub label: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Wav {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved 16-bit PCM.
+    pub samples: Vec<i16>,
+    pub info: Info,
+    pub cues: Vec<Cue>,
+}
+
+impl Wav {
+    pub fn mono(samples: Vec<i16>) -> Self {
+        Self {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            samples,
+            info: Info::default(),
+            cues: vec![],
+        }
+    }
+
+    /// Reads integer (8/16/24/32-bit) or 32-bit float PCM into 16-bit samples.
+    /// Metadata chunks are skipped.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            bail!("Not a RIFF/WAVE file");
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut rest = &bytes[12..];
+
+        while rest.len() >= 8 {
+            let id = &rest[0..4];
+            let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+
+            // Sizes near 4 GiB wrap around on 32-bit targets
+            let Some(end) = size.checked_add(8) else {
+                break;
+            };
+
+            let payload = rest.get(8..end).unwrap_or(&rest[8..]);
+
+            match id {
+                b"fmt " if payload.len() >= 16 => {
+                    let u16_at = |i: usize| u16::from_le_bytes([payload[i], payload[i + 1]]);
+                    let mut tag = u16_at(0);
+
+                    if tag == WAVE_FORMAT_EXTENSIBLE && payload.len() >= 26 {
+                        tag = u16_at(24);
+                    }
+
+                    format = Some((
+                        tag,
+                        u16_at(2),
+                        u32::from_le_bytes(payload[4..8].try_into().unwrap()),
+                        u16_at(12),
+                        u16_at(14),
+                    ));
+                }
+                b"data" => data = Some(payload),
+                _ => (),
+            }
+
+            rest = match end.checked_add(size % 2) {
+                Some(next) => rest.get(next..).unwrap_or_default(),
+                None => break,
+            };
+        }
+
+        let (tag, channels, sample_rate, block_align, bits) =
+            format.context("Missing fmt chunk")?;
+        let data = data.context("Missing data chunk")?;
+
+        if channels == 0 {
+            bail!("Invalid channel count");
+        }
+
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+            bail!("Unsupported sample rate {sample_rate} Hz");
+        }
+
+        if block_align as u32 != channels as u32 * (bits as u32).div_ceil(8) {
+            bail!("Block align {block_align} does not match {channels} channels of {bits} bits");
+        }
+
+        let samples = match (tag, bits) {
+            (WAVE_FORMAT_PCM, 8) => data.iter().map(|b| (*b as i16 - 128) << 8).collect(),
+            (WAVE_FORMAT_PCM, 16) => crate::audio::samples_from_bytes(data),
+            (WAVE_FORMAT_PCM, 24) => data
+                .chunks_exact(3)
+                .map(|b| i16::from_le_bytes([b[1], b[2]]))
+                .collect(),
+            (WAVE_FORMAT_PCM, 32) => data
+                .chunks_exact(4)
+                .map(|b| i16::from_le_bytes([b[2], b[3]]))
+                .collect(),
+            (WAVE_FORMAT_IEEE_FLOAT, 32) => data
+                .chunks_exact(4)
+                .map(|b| {
+                    let s = f32::from_le_bytes(b.try_into().unwrap());
+                    (s * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+                })
+                .collect(),
+            _ => bail!("Unsupported WAV format {tag} ({bits} bits)"),
+        };
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples,
+            info: Info::default(),
+            cues: vec![],
+        })
+    }
+
+    /// Averages the channels into a single one at the server's sample rate.
+    pub fn to_mono(&self) -> Vec<i16> {
+        let mono: Vec<f32> = self
+            .samples
+            .chunks_exact(self.channels as usize)
+            .map(|frame| frame.iter().map(|s| *s as f32).sum::<f32>() / self.channels as f32)
+            .collect();
+
+        resample(&mono, self.sample_rate as f32 / SAMPLE_RATE as f32)
+            .iter()
+            .map(|s| s.clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let data = samples_to_bytes(&self.samples);
+        let mut file = self.header(data.len() as u32);
+        file.extend(data);
+        file
+    }
+
+    /// The file up to the payload of its `data` chunk, for `data_bytes`
+    /// bytes of samples written after it. `samples` is not used, so long
+    /// files can be streamed.
+    pub fn header(&self, data_bytes: u32) -> Vec<u8> {
+        let block_align = self.channels * 2;
+
+        let mut fmt = vec![];
+        fmt.extend(1u16.to_le_bytes()); // PCM
+        fmt.extend(self.channels.to_le_bytes());
+        fmt.extend(self.sample_rate.to_le_bytes());
+        fmt.extend((self.sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(16u16.to_le_bytes());
+
+        let mut body = b"WAVE".to_vec();
+        write_chunk(&mut body, b"fmt ", &fmt);
+
+        let info = [
+            (b"INAM", &self.info.name),
+            (b"IART", &self.info.artist),
+            (b"ICMT", &self.info.comment),
+            (b"ISFT", &self.info.software),
+            (b"ICRD", &self.info.creation_date),
+        ];
+
+        if info.iter().any(|(_, v)| v.is_some()) {
+            let mut list = b"INFO".to_vec();
+            for (id, value) in info {
+                if let Some(value) = value {
+                    write_chunk(&mut list, id, &nul_terminated(value));
+                }
+            }
+            write_chunk(&mut body, b"LIST", &list);
+        }
+
+        if !self.cues.is_empty() {
+            let mut cue = (self.cues.len() as u32).to_le_bytes().to_vec();
+            let mut adtl = b"adtl".to_vec();
+
+            for (id, c) in (1u32..).zip(&self.cues) {
+                cue.extend(id.to_le_bytes());
+                cue.extend(c.position.to_le_bytes());
+                cue.extend(b"data");
+                cue.extend(0u32.to_le_bytes()); // chunk start
+                cue.extend(0u32.to_le_bytes()); // block start
+                cue.extend(c.position.to_le_bytes());
+
+                let mut labl = id.to_le_bytes().to_vec();
+                labl.extend(nul_terminated(&c.label));
+                write_chunk(&mut adtl, b"labl", &labl);
+            }
+
+            write_chunk(&mut body, b"cue ", &cue);
+            write_chunk(&mut body, b"LIST", &adtl);
+        }
+
+        body.extend(b"data");
+        body.extend(data_bytes.to_le_bytes());
+
+        let mut file = b"RIFF".to_vec();
+        file.extend((body.len() as u32 + data_bytes).to_le_bytes());
+        file.extend(body);
+        file
+    }
+}
+
+fn nul_terminated(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn write_chunk(dest: &mut Vec<u8>, id: &[u8; 4], payload: &[u8]) {
+    dest.extend(id);
+    dest.extend((payload.len() as u32).to_le_bytes());
+    dest.extend(payload);
+
+    // Chunks are word aligned; the pad byte is not counted in the size
+    if payload.len() % 2 == 1 {
+        dest.push(0);
+    }
+}
+
+/// Today's date (UTC) in the `YYYY-MM-DD` form expected by `ICRD`.
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+        / 86400;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+use anyhow::{Context, Result, bail};
+
+use super::{Book, Chapter};
+
+/// Bytes decompressed from one EPUB at most, so that a zip bomb fails
+/// instead of exhausting the memory.
+const MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Opening tags of `xml` as `(local name, attributes)`.
+fn tags(xml: &str) -> impl Iterator<Item = (&str, &str)> {
+    xml.split('<').skip(1).filter_map(|tag| {
+        let tag = tag.split_once('>')?.0.trim_end_matches('/');
+        let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+
+        // Drop namespace prefixes such as `opf:`
+        Some((name.rsplit(':').next().unwrap(), attrs))
+    })
+}
+
+fn attr(attrs: &str, key: &str) -> Option<String> {
+    let pattern = format!("{key}=");
+
+    let (start, _) = attrs
+        .match_indices(&pattern)
+        .find(|(i, _)| attrs[..*i].ends_with(char::is_whitespace) || *i == 0)?;
+
+    let value = &attrs[start + pattern.len()..];
+    let quote = value.chars().next()?;
+    let value = value[1..].split(quote).next()?;
+
+    Some(decode_entities(value))
+}
+
+/// Decodes the XML entities, the common HTML ones and character references.
+/// Unknown entities are kept as they are.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..]
+            .split_once(';')
+            .map(|(e, _)| e)
+            .filter(|e| e.len() < 10);
+
+        let c = entity.and_then(|entity| match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                .and_then(char::from_u32),
+        });
+
+        match (entity, c) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Reads a text file of the EPUB, taking its size from `budget`.
+fn read(zip: &mut zip::ZipArchive<Cursor<&[u8]>>, path: &str, budget: &mut u64) -> Result<String> {
+    let file = zip
+        .by_name(path)
+        .context(format!("Missing {path} in EPUB"))?;
+
+    let mut content = vec![];
+    file.take(*budget + 1)
+        .read_to_end(&mut content)
+        .context(format!("Failed to read {path} from EPUB"))?;
+
+    if content.len() as u64 > *budget {
+        bail!("EPUB expands to more than {} MiB", MAX_BYTES / 1024 / 1024);
+    }
+
+    *budget -= content.len() as u64;
+
+    String::from_utf8(content).context(format!("{path} in EPUB is not UTF-8"))
+}
+
+/// `href` relative to the directory `base`, without its fragment.
+fn resolve(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap().replace("%20", " ");
+    let mut parts: Vec<_> = base.split('/').filter(|p| !p.is_empty()).collect();
+
+    for part in href.split('/') {
+        match part {
+            "." | "" => (),
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    parts.join("/")
+}
+
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div"
+            | "br"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "li"
+            | "tr"
+            | "section"
+            | "blockquote"
+    )
+}
+
+/// Title and text of an XHTML document, one line per block. With
+/// `keep_ruby`, `<ruby>` elements are kept for the engine to read;
+/// otherwise their readings are dropped.
+fn xhtml_text(xhtml: &str, keep_ruby: bool) -> (Option<String>, String) {
+    let head_title = xhtml
+        .find("<title>")
+        .and_then(|start| xhtml[start + 7..].split_once("</title>"))
+        .map(|(title, _)| decode_entities(title).trim().to_string())
+        .filter(|title| !title.is_empty());
+
+    let body = xhtml.find("<body").map_or(xhtml, |start| &xhtml[start..]);
+
+    let mut text = String::new();
+    let mut heading: Option<String> = None;
+    let mut in_heading = false;
+    let mut skip: Option<String> = None;
+
+    for (i, piece) in body.split('<').enumerate() {
+        let (tag, content) = match i {
+            0 => ("", piece),
+            _ => piece.split_once('>').unwrap_or((piece, "")),
+        };
+
+        let tag = tag.trim_end_matches('/');
+        let name = tag
+            .split(char::is_whitespace)
+            .next()
+            .unwrap()
+            .to_lowercase();
+        let (closing, local) = match name.strip_prefix('/') {
+            Some(local) => (true, local.to_string()),
+            None => (false, name.clone()),
+        };
+
+        if let Some(until) = &skip {
+            if closing && local == *until {
+                skip = None;
+            } else {
+                continue;
+            }
+        } else {
+            match local.as_str() {
+                "script" | "style" | "rp" if !closing => {
+                    skip = Some(local);
+                    continue;
+                }
+                "rt" if !closing && !keep_ruby => {
+                    skip = Some(local);
+                    continue;
+                }
+                "ruby" | "rb" | "rt" if keep_ruby => text.push_str(&format!("<{name}>")),
+                "h1" | "h2" | "h3" if heading.is_none() || in_heading => {
+                    in_heading = !closing;
+                    text.push('\n');
+                }
+                local if is_block(local) => text.push('\n'),
+                _ => (),
+            }
+        }
+
+        let content = decode_entities(content);
+
+        if in_heading {
+            heading.get_or_insert_with(String::new).push_str(&content);
+        }
+
+        text.push_str(&content);
+    }
+
+    let text = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let heading = heading
+        .map(|heading| heading.trim().to_string())
+        .filter(|heading| !heading.is_empty());
+
+    (heading.or(head_title), text)
+}
+
+/// Reads the documents of the spine, in order, as chapters. Documents
+/// without text (covers, blank pages) are skipped.
+pub fn parse(bytes: &[u8], keep_ruby: bool) -> Result<Book> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).context("Failed to open EPUB")?;
+
+    let mut budget = MAX_BYTES;
+    let container = read(&mut zip, "META-INF/container.xml", &mut budget)?;
+
+    let opf_path = tags(&container)
+        .find(|(name, _)| *name == "rootfile")
+        .and_then(|(_, attrs)| attr(attrs, "full-path"))
+        .context("Missing rootfile in container.xml")?;
+
+    let opf = read(&mut zip, &opf_path, &mut budget)?;
+    let base = opf_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+
+    let manifest: HashMap<_, _> = tags(&opf)
+        .filter(|(name, _)| *name == "item")
+        .filter_map(|(_, attrs)| Some((attr(attrs, "id")?, attr(attrs, "href")?)))
+        .collect();
+
+    let spine: Vec<_> = tags(&opf)
+        .filter(|(name, attrs)| {
+            *name == "itemref" && attr(attrs, "linear").as_deref() != Some("no")
+        })
+        .filter_map(|(_, attrs)| attr(attrs, "idref"))
+        .collect();
+
+    let title = opf
+        .find("<dc:title")
+        .and_then(|start| opf[start..].split_once('>'))
+        .and_then(|(_, rest)| rest.split_once("</dc:title>"))
+        .map(|(title, _)| decode_entities(title).trim().to_string())
+        .filter(|title| !title.is_empty());
+
+    let mut chapters = vec![];
+
+    for idref in spine {
+        let href = manifest
+            .get(&idref)
+            .context(format!("Spine item {idref} is not in the manifest"))?;
+
+        let xhtml = read(&mut zip, &resolve(base, href), &mut budget)?;
+        let (heading, text) = xhtml_text(&xhtml, keep_ruby);
+
+        if text.is_empty() {
+            continue;
+        }
+
+        chapters.push(Chapter {
+            title: heading.unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1)),
+            text,
+        });
+    }
+
+    Ok(Book { title, chapters })
+}
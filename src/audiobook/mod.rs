@@ -0,0 +1,441 @@
+//! Audiobooks: EPUB or plain-text uploads synthesized chapter by chapter in
+//! the background.
+//!
+//! Every book gets a directory holding one WAV per chapter, optionally the
+//! whole book as a single WAV, and `index.json`. Progress is kept in memory;
+//! the files outlive restarts.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::audio::{
+    SAMPLE_RATE, ms_to_samples, samples_to_bytes,
+    wav::{Cue, Info, Wav},
+};
+use crate::limits::Limits;
+use crate::model::{
+    ApiRequest, AudiobookParams, AudiobookState, AudiobookStatus, ChapterStatus, Request,
+};
+use crate::synthesis::{Rejection, Workers};
+
+pub mod epub;
+
+/// Chapters are sent to the workers in pieces of at most this many
+/// characters, so that other requests are served in between.
+const PIECE_CHARS: usize = 500;
+
+/// Silence between the pieces of a chapter.
+const PIECE_GAP_MS: u32 = 800;
+
+const INDEX_FILE: &str = "index.json";
+const BOOK_FILE: &str = "book.wav";
+
+static DIR: OnceCell<PathBuf> = OnceCell::new();
+static JOBS: Lazy<Mutex<HashMap<String, Arc<Job>>>> = Lazy::new(Default::default);
+
+pub struct Book {
+    pub title: Option<String>,
+    pub chapters: Vec<Chapter>,
+}
+
+pub struct Chapter {
+    pub title: String,
+    pub text: String,
+}
+
+struct Job {
+    status: Mutex<AudiobookStatus>,
+    cancelled: AtomicBool,
+}
+
+impl Job {
+    fn update(&self, f: impl FnOnce(&mut AudiobookStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    fn snapshot(&self) -> AudiobookStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Creates the directory audiobooks are written to.
+pub fn init(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).context("Failed to create audiobook directory")?;
+    DIR.get_or_init(|| dir.to_path_buf());
+
+    Ok(())
+}
+
+/// Plain text, split into chapters at lines starting with `#` (the rest of
+/// the line being the title). Text before the first heading is a chapter of
+/// its own.
+pub fn plain(text: &str) -> Book {
+    let mut chapters: Vec<Chapter> = vec![];
+    let mut current = Chapter {
+        title: String::new(),
+        text: String::new(),
+    };
+
+    for line in text.lines() {
+        match line.strip_prefix('#') {
+            Some(title) => {
+                chapters.push(current);
+                current = Chapter {
+                    title: title.trim_start_matches('#').trim().to_string(),
+                    text: String::new(),
+                };
+            }
+            None => {
+                current.text.push_str(line);
+                current.text.push('\n');
+            }
+        }
+    }
+
+    chapters.push(current);
+    chapters.retain(|chapter| !chapter.text.trim().is_empty());
+
+    for (i, chapter) in chapters.iter_mut().enumerate() {
+        if chapter.title.is_empty() {
+            chapter.title = format!("Chapter {}", i + 1);
+        }
+    }
+
+    Book {
+        title: None,
+        chapters,
+    }
+}
+
+/// IDs are hexadecimal; anything else cannot name a book.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Reads an uploaded EPUB or UTF-8 text.
+fn read(upload: &[u8], keep_ruby: bool) -> Result<Book, Rejection> {
+    let bad_request = |e| Rejection::new(StatusCode::BAD_REQUEST, e);
+
+    let book = if upload.starts_with(b"PK\x03\x04") {
+        epub::parse(upload, keep_ruby).map_err(|e| bad_request(format!("{e:#}")))?
+    } else {
+        let text = std::str::from_utf8(upload)
+            .map_err(|_| bad_request("Upload is neither an EPUB nor UTF-8 text".to_string()))?;
+        plain(text)
+    };
+
+    if book.chapters.is_empty() {
+        return Err(bad_request("No text found".to_string()));
+    }
+
+    Ok(book)
+}
+
+/// Starts synthesizing an uploaded EPUB or UTF-8 text and returns its status.
+pub async fn start(
+    workers: &Workers,
+    upload: Bytes,
+    params: AudiobookParams,
+    limits: &'static Limits,
+) -> Result<AudiobookStatus, Rejection> {
+    let keep_ruby = params.ruby;
+    let book = tokio::task::spawn_blocking(move || read(&upload, keep_ruby))
+        .await
+        .unwrap()?;
+
+    let is_kansai = crate::synthesis::validate(&ApiRequest {
+        is_kansai: params.is_kansai,
+        deadline_ms: None,
+        max_queue_wait_ms: None,
+        queue_key: None,
+        adaptive_speed: false,
+        body: Request::new(&params.voice_id, ""),
+    })?;
+
+    let id = format!(
+        "{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+
+    let dir = DIR.get().unwrap().join(&id);
+
+    std::fs::create_dir_all(&dir).map_err(|e| {
+        Rejection::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create {}: {e}", dir.display()),
+        )
+    })?;
+
+    let job = Arc::new(Job {
+        status: Mutex::new(AudiobookStatus {
+            id: id.clone(),
+            title: book.title.clone(),
+            voice_id: params.voice_id.clone(),
+            state: AudiobookState::Running,
+            progress: 0.0,
+            error: None,
+            chapters: book
+                .chapters
+                .iter()
+                .map(|chapter| ChapterStatus {
+                    title: chapter.title.clone(),
+                    characters: chapter.text.chars().count(),
+                    file: None,
+                    duration: None,
+                })
+                .collect(),
+            file: None,
+        }),
+        cancelled: AtomicBool::new(false),
+    });
+
+    JOBS.lock().unwrap().insert(id.clone(), job.clone());
+
+    tracing::info!("Audiobook {id}: {} chapters", book.chapters.len());
+
+    tokio::spawn({
+        let workers = workers.clone();
+        let job = job.clone();
+
+        async move {
+            let result = synthesize(&workers, &job, book, &params, is_kansai, limits, &dir).await;
+
+            job.update(|status| match result {
+                Ok(()) => (),
+                Err(_) if job.cancelled.load(Ordering::Relaxed) => {
+                    status.state = AudiobookState::Cancelled;
+                }
+                Err(e) => {
+                    tracing::warn!("Audiobook {}: {e:#}", status.id);
+                    status.state = AudiobookState::Failed;
+                    status.error = Some(format!("{e:#}"));
+                }
+            });
+        }
+    });
+
+    Ok(job.snapshot())
+}
+
+async fn synthesize(
+    workers: &Workers,
+    job: &Job,
+    book: Book,
+    params: &AudiobookParams,
+    is_kansai: bool,
+    limits: &Limits,
+    dir: &Path,
+) -> Result<()> {
+    let id = job.snapshot().id;
+    let queue_key = format!("audiobook:{id}");
+    let piece_chars = limits
+        .max_chars
+        .map_or(PIECE_CHARS, |max| max.min(PIECE_CHARS));
+
+    let total: usize = book.chapters.iter().map(|c| c.text.chars().count()).sum();
+    let mut done = 0;
+
+    for (index, chapter) in book.chapters.iter().enumerate() {
+        let mut samples = vec![];
+
+        for (i, piece) in crate::text::chunk::split(&chapter.text, piece_chars)
+            .into_iter()
+            .enumerate()
+        {
+            if job.cancelled.load(Ordering::Relaxed) {
+                bail!("Cancelled");
+            }
+
+            let mut body = Request {
+                speed: params.speed,
+                ruby: params.ruby,
+                ..Request::new(&params.voice_id, &piece)
+            };
+
+            limits.apply(&mut body).map_err(anyhow::Error::msg)?;
+
+            let wav = workers
+                .dispatch(body, is_kansai, &queue_key)
+                .await
+                .context(format!("Failed to synthesize chapter {}", index + 1))?;
+
+            if i > 0 {
+                samples.resize(samples.len() + ms_to_samples(PIECE_GAP_MS), 0);
+            }
+
+            samples.extend(Wav::decode(&wav)?.to_mono());
+
+            done += piece.chars().count();
+            job.update(|status| status.progress = done as f32 / total as f32);
+        }
+
+        let file = format!("{:03}.wav", index + 1);
+        let duration = samples.len() as f32 / SAMPLE_RATE as f32;
+
+        let wav = Wav {
+            info: Info {
+                name: Some(chapter.title.clone()),
+                artist: Some(params.voice_id.clone()),
+                ..Info::default()
+            },
+            ..Wav::mono(samples)
+        };
+
+        let path = dir.join(&file);
+        tokio::task::spawn_blocking(move || std::fs::write(path, wav.encode()))
+            .await
+            .unwrap()
+            .context(format!("Failed to write {file}"))?;
+
+        job.update(|status| {
+            status.chapters[index].file = Some(file);
+            status.chapters[index].duration = Some(duration);
+        });
+    }
+
+    if params.single_file {
+        let dir = dir.to_path_buf();
+        let status = job.snapshot();
+
+        tokio::task::spawn_blocking(move || join_chapters(&dir, &status))
+            .await
+            .unwrap()?;
+
+        job.update(|status| status.file = Some(BOOK_FILE.to_string()));
+    }
+
+    job.update(|status| {
+        status.state = AudiobookState::Done;
+        status.progress = 1.0;
+    });
+
+    std::fs::write(
+        dir.join(INDEX_FILE),
+        serde_json::to_vec_pretty(&job.snapshot())?,
+    )
+    .context(format!("Failed to write {INDEX_FILE}"))?;
+
+    tracing::info!("Audiobook {id} done");
+
+    Ok(())
+}
+
+/// Writes the chapters as a single WAV with a cue at the start of each,
+/// holding one chapter in memory at a time.
+fn join_chapters(dir: &Path, status: &AudiobookStatus) -> Result<()> {
+    let read = |file: &str| -> Result<Vec<i16>> {
+        let bytes = std::fs::read(dir.join(file)).context(format!("Failed to read {file}"))?;
+        Ok(Wav::decode(&bytes)?.samples)
+    };
+
+    let chapters: Vec<_> = status
+        .chapters
+        .iter()
+        .filter_map(|chapter| Some((chapter.file.as_deref()?, &chapter.title)))
+        .collect();
+
+    // The cues go before the samples, so the lengths are needed first
+    let mut cues = vec![];
+    let mut length = 0u64;
+
+    for (file, title) in &chapters {
+        cues.push(Cue {
+            position: length as u32,
+            label: title.to_string(),
+        });
+
+        length += read(file)?.len() as u64;
+    }
+
+    let Ok(data_bytes) = u32::try_from(length * 2) else {
+        bail!("The book is too long for a single WAV");
+    };
+
+    let header = Wav {
+        info: Info {
+            name: status.title.clone(),
+            artist: Some(status.voice_id.clone()),
+            ..Info::default()
+        },
+        cues,
+        ..Wav::mono(vec![])
+    }
+    .header(data_bytes);
+
+    let failed = || format!("Failed to write {BOOK_FILE}");
+    let mut out = BufWriter::new(File::create(dir.join(BOOK_FILE)).with_context(failed)?);
+    out.write_all(&header).with_context(failed)?;
+
+    for (file, _) in &chapters {
+        out.write_all(&samples_to_bytes(&read(file)?))
+            .with_context(failed)?;
+    }
+
+    out.flush().with_context(failed)
+}
+
+pub fn status(id: &str) -> Option<AudiobookStatus> {
+    JOBS.lock().unwrap().get(id).map(|job| job.snapshot())
+}
+
+/// Books started since the server started, oldest first.
+pub fn list() -> Vec<AudiobookStatus> {
+    let mut books: Vec<_> = JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|job| job.snapshot())
+        .collect();
+
+    books.sort_by(|a, b| a.id.cmp(&b.id));
+    books
+}
+
+/// Cancels a book if it is still running and deletes its files. Returns
+/// whether it existed.
+pub fn remove(id: &str) -> Result<bool> {
+    if !is_valid_id(id) {
+        return Ok(false);
+    }
+
+    if let Some(job) = JOBS.lock().unwrap().remove(id) {
+        job.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    let dir = DIR.get().unwrap().join(id);
+
+    if !dir.exists() {
+        return Ok(false);
+    }
+
+    std::fs::remove_dir_all(&dir).context(format!("Failed to delete {}", dir.display()))?;
+
+    Ok(true)
+}
+
+/// Reads a finished file of a book, or `None` if there is no such file.
+pub fn file(id: &str, name: &str) -> Option<Vec<u8>> {
+    let valid_name = !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+
+    if !is_valid_id(id) || !valid_name {
+        return None;
+    }
+
+    std::fs::read(DIR.get().unwrap().join(id).join(name)).ok()
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use crate::audio::effects::Effect;
+use crate::model::Request;
+
+static CACHE: OnceCell<Cache> = OnceCell::new();
+
+struct Cache {
+    memory: Mutex<Tier<Arc<Vec<u8>>>>,
+    disk: Option<(PathBuf, Mutex<Tier<()>>)>,
+    dictionary_version: RwLock<String>,
+    max_chunk_chars: usize,
+}
+
+/// Size-bounded LRU index; recency is a tick bumped on every access.
+pub struct Tier<T> {
+    entries: HashMap<String, (T, u64, u64)>,
+    bytes: u64,
+    limit: u64,
+    tick: u64,
+}
+
+impl<T: Clone> Tier<T> {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            bytes: 0,
+            limit,
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<T> {
+        self.tick += 1;
+        let (value, _, used) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        Some(value.clone())
+    }
+
+    /// Returns the keys evicted to make room.
+    pub fn insert(&mut self, key: String, value: T, size: u64) -> Vec<String> {
+        if size > self.limit {
+            return vec![];
+        }
+
+        self.tick += 1;
+
+        if let Some((_, old, _)) = self.entries.insert(key, (value, size, self.tick)) {
+            self.bytes -= old;
+        }
+
+        self.bytes += size;
+
+        let mut evicted = vec![];
+
+        while self.bytes > self.limit {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+
+            let (_, size, _) = self.entries.remove(&oldest).unwrap();
+            self.bytes -= size;
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+
+    pub fn clear(&mut self) -> Vec<String> {
+        self.bytes = 0;
+        self.entries.drain().map(|(key, _)| key).collect()
+    }
+}
+
+fn cache() -> &'static Cache {
+    CACHE.get().unwrap()
+}
+
+fn disk_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{key}.wav"))
+}
+
+/// Sets up the cache. The disk tier keeps what is already in `disk` (trimmed to
+/// `disk_limit`) and is disabled when `disk` is `None`.
+pub fn init(
+    memory_limit: u64,
+    disk: Option<(&Path, u64)>,
+    dictionary_version: String,
+    max_chunk_chars: usize,
+) -> Result<()> {
+    let disk = match disk {
+        Some((dir, limit)) => {
+            std::fs::create_dir_all(dir).context("Failed to create cache directory")?;
+
+            let mut files = vec![];
+
+            for entry in std::fs::read_dir(dir).context("Failed to read cache directory")? {
+                let entry = entry?;
+                let path = entry.path();
+
+                if path.extension().is_none_or(|ext| ext != "wav") {
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+                let key = path.file_stem().unwrap().to_string_lossy().to_string();
+                files.push((metadata.modified()?, key, metadata.len()));
+            }
+
+            // Oldest first, so the most recently written files survive trimming
+            files.sort();
+
+            let mut tier = Tier::new(limit);
+
+            for (_, key, size) in files {
+                for evicted in tier.insert(key, (), size) {
+                    let _ = std::fs::remove_file(disk_path(dir, &evicted));
+                }
+            }
+
+            tracing::info!("{} cached results on disk", tier.entries.len());
+
+            Some((dir.to_path_buf(), Mutex::new(tier)))
+        }
+        None => None,
+    };
+
+    CACHE.get_or_init(|| Cache {
+        memory: Mutex::new(Tier::new(memory_limit)),
+        disk,
+        dictionary_version: RwLock::new(dictionary_version),
+        max_chunk_chars,
+    });
+
+    Ok(())
+}
+
+pub fn set_dictionary_version(version: String) {
+    *cache().dictionary_version.write().unwrap() = version;
+}
+
+#[derive(Serialize)]
+struct Key<'a> {
+    is_kansai: bool,
+    dictionary_version: &'a str,
+
+    /// Server settings that change the output; the disk tier outlives them.
+    max_chunk_chars: usize,
+    watermark: Option<(u16, f32)>,
+
+    /// What the effect preset and the background asset, which the request
+    /// only names, currently hold.
+    effect_preset: Option<&'static [Effect]>,
+    background_digest: Option<String>,
+
+    request: &'a Request,
+}
+
+/// Cache key of a request: a digest of every parameter that affects the output.
+pub fn key(request: &Request, is_kansai: bool) -> String {
+    let mut request = request.clone();
+    request.text = request.text.trim().to_string();
+
+    let key = Key {
+        is_kansai,
+        dictionary_version: &cache().dictionary_version.read().unwrap(),
+        max_chunk_chars: cache().max_chunk_chars,
+        watermark: crate::audio::watermark::settings(),
+        effect_preset: request
+            .effect_preset
+            .as_deref()
+            .and_then(crate::audio::effects::preset),
+        background_digest: request
+            .background
+            .as_ref()
+            .and_then(|background| crate::assets::digest(&background.asset)),
+        request: &request,
+    };
+
+    format!("{:x}", Sha1::digest(serde_json::to_vec(&key).unwrap()))
+}
+
+/// Looks up the memory tier, then the disk tier. Disk hits are promoted.
+pub fn get(key: &str) -> Option<Arc<Vec<u8>>> {
+    let cache = cache();
+
+    if let Some(wav) = cache.memory.lock().unwrap().get(key) {
+        return Some(wav);
+    }
+
+    let (dir, tier) = cache.disk.as_ref()?;
+    tier.lock().unwrap().get(key)?;
+
+    let wav = match std::fs::read(disk_path(dir, key)) {
+        Ok(wav) => Arc::new(wav),
+        Err(e) => {
+            tracing::warn!("Failed to read cached result {key}: {e}");
+            return None;
+        }
+    };
+
+    cache
+        .memory
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), wav.clone(), wav.len() as u64);
+
+    Some(wav)
+}
+
+pub fn put(key: &str, wav: Arc<Vec<u8>>) {
+    let cache = cache();
+
+    let size = wav.len() as u64;
+
+    cache
+        .memory
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), wav.clone(), size);
+
+    let Some((dir, tier)) = &cache.disk else {
+        return;
+    };
+
+    if size > tier.lock().unwrap().limit {
+        return;
+    }
+
+    if let Err(e) = std::fs::write(disk_path(dir, key), wav.as_slice()) {
+        tracing::warn!("Failed to write cached result {key}: {e}");
+        return;
+    }
+
+    for evicted in tier.lock().unwrap().insert(key.to_string(), (), size) {
+        let _ = std::fs::remove_file(disk_path(dir, &evicted));
+    }
+}
+
+/// Drops every cached result from both tiers. Returns how many were removed.
+pub fn purge() -> usize {
+    let cache = cache();
+
+    let mut purged = cache.memory.lock().unwrap().clear().len();
+
+    if let Some((dir, tier)) = &cache.disk {
+        let keys = tier.lock().unwrap().clear();
+        purged = purged.max(keys.len());
+
+        for key in keys {
+            let _ = std::fs::remove_file(disk_path(dir, &key));
+        }
+    }
+
+    purged
+}
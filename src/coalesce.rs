@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::oneshot;
+
+use crate::synthesis::Rejection;
+
+pub type Outcome = Result<Arc<Vec<u8>>, Rejection>;
+
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, Vec<oneshot::Sender<Outcome>>>>> =
+    Lazy::new(Default::default);
+
+pub enum Flight {
+    /// Nobody is synthesizing this request yet; the caller has to.
+    Leader(Leader),
+
+    /// Resolves when the leader finishes. Fails if the leader was cancelled.
+    Follower(oneshot::Receiver<Outcome>),
+}
+
+/// Shares its outcome with the followers when dropped. Followers of a leader
+/// dropped without an outcome see their channel closed.
+pub struct Leader {
+    key: String,
+    outcome: Option<Outcome>,
+}
+
+impl Leader {
+    pub fn finish(mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        let waiters = IN_FLIGHT
+            .lock()
+            .unwrap()
+            .remove(&self.key)
+            .unwrap_or_default();
+
+        if let Some(outcome) = &self.outcome {
+            for waiter in waiters {
+                let _ = waiter.send(outcome.clone());
+            }
+        }
+    }
+}
+
+/// Joins the in-flight synthesis of `key`, or starts one.
+pub fn join(key: &str) -> Flight {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+
+    match in_flight.get_mut(key) {
+        Some(waiters) => {
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            Flight::Follower(rx)
+        }
+        None => {
+            in_flight.insert(key.to_string(), vec![]);
+            Flight::Leader(Leader {
+                key: key.to_string(),
+                outcome: None,
+            })
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Skits: lines of several voices synthesized separately and joined into a
+//! single track.
+
+use std::collections::{BTreeMap, HashMap};
+use std::f32::consts::FRAC_PI_4;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::audio::{SAMPLE_RATE, ms_to_samples, wav::Cue, wav::Wav};
+use crate::limits::{Limits, OutputLimit, TooLong};
+use crate::model::{ApiRequest, DialogueLine, DialogueRequest, LineTiming, Novel, Request};
+use crate::synthesis::{Rejection, Workers};
+
+/// Longest gap accepted after a line.
+const MAX_GAP_MS: u32 = 10_000;
+
+/// Most lines in a dialogue, all of which are queued at once.
+const MAX_LINES: usize = 500;
+
+pub struct Dialogue {
+    pub wav: Wav,
+    pub lines: Vec<LineTiming>,
+
+    /// Per voice ID, only filled when stems are requested.
+    pub stems: Vec<(String, Wav)>,
+}
+
+/// A synthesized line waiting to be joined.
+struct Line {
+    voice: Arc<Vec<u8>>,
+    voice_id: String,
+    text: String,
+    gap_ms: u32,
+}
+
+/// Left and right gains of a constant-power pan, `position` going from -1.0
+/// (left) to 1.0 (right).
+fn pan_gains(position: f32) -> (f32, f32) {
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+/// Lines of a novel, each spoken with the default prosody.
+fn novel_lines(novel: Novel) -> Vec<DialogueLine> {
+    let dialogue = novel.dialogue.as_deref().unwrap_or(&novel.narrator);
+
+    crate::text::novel::split(&novel.text, &novel.narrator, dialogue, &novel.speakers)
+        .into_iter()
+        .map(|(voice_id, text)| DialogueLine {
+            is_kansai: None,
+            gap_ms: None,
+            body: Request {
+                ruby: novel.ruby,
+                ..Request::new(&voice_id, &text)
+            },
+        })
+        .collect()
+}
+
+fn line_error(index: usize, rejection: Rejection) -> Rejection {
+    Rejection {
+        message: format!("Line {index}: {}", rejection.message),
+        ..rejection
+    }
+}
+
+/// Synthesizes every line (each on the worker of its dialect, through the
+/// cache) and joins them with their gaps.
+pub async fn synthesize(
+    workers: &Workers,
+    mut request: DialogueRequest,
+    limits: &Limits,
+) -> Result<Dialogue, Rejection> {
+    if let Some(novel) = request.novel.take() {
+        if !request.lines.is_empty() {
+            return Err(Rejection::new(
+                StatusCode::BAD_REQUEST,
+                "Give either lines or novel",
+            ));
+        }
+
+        request.lines = novel_lines(novel);
+    }
+
+    if request.lines.is_empty() {
+        return Err(Rejection::new(StatusCode::BAD_REQUEST, "No lines"));
+    }
+
+    if request.lines.len() > MAX_LINES {
+        return Err(Rejection::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "{} lines, at most {MAX_LINES} are allowed",
+                request.lines.len()
+            ),
+        ));
+    }
+
+    let queue_key = request
+        .queue_key
+        .take()
+        .unwrap_or_else(|| crate::synthesis::batch_key("dialogue"));
+
+    let mut api_reqs = vec![];
+    let mut gaps = vec![];
+
+    // Reject the whole dialogue before synthesizing any of it
+    for (index, line) in request.lines.into_iter().enumerate() {
+        let mut api_req = ApiRequest {
+            is_kansai: line.is_kansai,
+            deadline_ms: request.deadline_ms,
+            max_queue_wait_ms: request.max_queue_wait_ms,
+            queue_key: Some(queue_key.clone()),
+            adaptive_speed: false,
+            body: line.body,
+        };
+
+        crate::synthesis::parse_text(&mut api_req.body).map_err(|e| line_error(index, e))?;
+        limits
+            .apply(&mut api_req.body)
+            .map_err(|e| line_error(index, Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, e)))?;
+        crate::synthesis::validate(&api_req).map_err(|e| line_error(index, e))?;
+
+        let gap_ms = line.gap_ms.unwrap_or(request.gap_ms);
+
+        if gap_ms > MAX_GAP_MS {
+            return Err(line_error(
+                index,
+                Rejection::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Gap of {gap_ms} ms exceeds {MAX_GAP_MS} ms"),
+                ),
+            ));
+        }
+
+        gaps.push(gap_ms);
+        api_reqs.push(api_req);
+    }
+
+    workers.admit(&api_reqs)?;
+
+    let texts: Vec<_> = api_reqs
+        .iter()
+        .map(|r| (r.body.voice_id.clone(), r.body.text.clone()))
+        .collect();
+
+    // Lines of both dialects are synthesized at the same time
+    let voices = workers
+        .synthesize_all(api_reqs)
+        .await
+        .map_err(|(index, rejection)| line_error(index, rejection))?;
+
+    let lines = voices
+        .into_iter()
+        .zip(texts)
+        .zip(gaps)
+        .map(|((voice, (voice_id, text)), gap_ms)| Line {
+            voice,
+            voice_id,
+            text,
+            gap_ms,
+        })
+        .collect();
+
+    let limit = limits.output_limit();
+
+    tokio::task::spawn_blocking(move || join(lines, &request.pan, request.stems, limit))
+        .await
+        .unwrap()
+        .map_err(|e| {
+            let status = if e.is::<TooLong>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+
+            Rejection::new(status, format!("{e:#}"))
+        })
+}
+
+/// Concatenates the synthesized lines, each followed by its gap, and holds
+/// the whole to `limit`. The result is stereo when any voice is panned.
+fn join(
+    lines: Vec<Line>,
+    pan: &HashMap<String, f32>,
+    stems: bool,
+    limit: Option<OutputLimit>,
+) -> Result<Dialogue> {
+    let channels = if pan.is_empty() { 1 } else { 2 };
+    let last = lines.len() - 1;
+
+    let mut samples = vec![];
+    let mut cues = vec![];
+    let mut timings = vec![];
+    let mut placed = vec![];
+
+    for (index, line) in lines.into_iter().enumerate() {
+        let voice = Wav::decode(&line.voice)?.to_mono();
+        let start = samples.len() / channels;
+
+        if channels == 1 {
+            samples.extend(&voice);
+        } else {
+            let (left, right) = pan_gains(pan.get(&line.voice_id).copied().unwrap_or(0.0));
+
+            samples.extend(
+                voice
+                    .iter()
+                    .flat_map(|s| [(*s as f32 * left) as i16, (*s as f32 * right) as i16]),
+            );
+        }
+
+        let end = samples.len() / channels;
+
+        if index != last {
+            samples.resize(samples.len() + ms_to_samples(line.gap_ms) * channels, 0);
+        }
+
+        cues.push(Cue {
+            position: start as u32,
+            label: line.voice_id.clone(),
+        });
+
+        let to_ms = |frames: usize| frames as u64 * 1000 / SAMPLE_RATE as u64;
+
+        timings.push(LineTiming {
+            voice_id: line.voice_id.clone(),
+            text: line.text,
+            start_ms: to_ms(start),
+            end_ms: to_ms(end),
+        });
+
+        if stems {
+            placed.push((line.voice_id, start, voice));
+        }
+    }
+
+    if let Some(limit) = limit {
+        // The limit counts frames, the mix may be interleaved
+        OutputLimit {
+            max_samples: limit.max_samples * channels,
+            fade_out: limit.fade_out.map(|fade_out| fade_out * channels),
+        }
+        .enforce(&mut samples, &mut vec![])?;
+
+        let frames = samples.len() / channels;
+        let max_ms = frames as u64 * 1000 / SAMPLE_RATE as u64;
+
+        cues.retain(|cue| (cue.position as usize) < frames);
+        timings.retain(|timing| timing.start_ms < max_ms);
+        timings
+            .iter_mut()
+            .for_each(|timing| timing.end_ms = timing.end_ms.min(max_ms));
+    }
+
+    // One unpanned track per voice, as long as the mix
+    let frames = samples.len() / channels;
+    let mut stems: BTreeMap<String, Vec<i16>> = BTreeMap::new();
+
+    for (voice_id, start, voice) in placed {
+        let stem = stems.entry(voice_id).or_insert_with(|| vec![0; frames]);
+
+        if start < frames {
+            let end = (start + voice.len()).min(frames);
+            stem[start..end].copy_from_slice(&voice[..end - start]);
+        }
+    }
+
+    Ok(Dialogue {
+        wav: Wav {
+            channels: channels as u16,
+            cues,
+            ..Wav::mono(samples)
+        },
+        lines: timings,
+        stems: stems
+            .into_iter()
+            .map(|(voice_id, samples)| (voice_id, Wav::mono(samples)))
+            .collect(),
+    })
+}
+
+impl Dialogue {
+    /// A ZIP of `mix.wav`, one `stems/<voice ID>.wav` per voice and the
+    /// timeline as `timeline.json` and `timeline.csv`.
+    pub fn to_zip(&self) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::default();
+
+        zip.start_file("mix.wav", options)?;
+        zip.write_all(&self.wav.encode())?;
+
+        for (voice_id, stem) in &self.stems {
+            zip.start_file(format!("stems/{voice_id}.wav"), options)?;
+            zip.write_all(&stem.encode())?;
+        }
+
+        zip.start_file("timeline.json", options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&self.lines)?)?;
+
+        zip.start_file("timeline.csv", options)?;
+        writeln!(zip, "index,voice_id,start_ms,end_ms,text")?;
+
+        for (index, line) in self.lines.iter().enumerate() {
+            writeln!(
+                zip,
+                "{index},{},{},{},\"{}\"",
+                line.voice_id,
+                line.start_ms,
+                line.end_ms,
+                line.text.replace('"', "\"\"")
+            )?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
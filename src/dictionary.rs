@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aitalked::{api::Aitalked, binding::ResultCode};
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use sha1::{Digest, Sha1};
+
+use crate::worker::path_to_sjis_cstring;
+
+static DICTIONARIES: OnceCell<Dictionaries> = OnceCell::new();
+
+struct Dictionaries {
+    word: Option<PathBuf>,
+    phrase: Option<PathBuf>,
+    symbol: Option<PathBuf>,
+
+    /// Bumped on every reload; workers compare it with the generation they
+    /// loaded to find out they are stale.
+    generation: AtomicU64,
+}
+
+fn dictionaries() -> &'static Dictionaries {
+    DICTIONARIES.get().unwrap()
+}
+
+pub fn init(word: Option<PathBuf>, phrase: Option<PathBuf>, symbol: Option<PathBuf>) {
+    DICTIONARIES.get_or_init(|| Dictionaries {
+        word,
+        phrase,
+        symbol,
+        generation: AtomicU64::new(0),
+    });
+}
+
+/// Digest of the dictionary files, so that results synthesized with other
+/// dictionaries are never served from the cache.
+pub fn version() -> Result<String> {
+    let dictionaries = dictionaries();
+    let mut hasher = Sha1::new();
+
+    for dic in [
+        &dictionaries.word,
+        &dictionaries.phrase,
+        &dictionaries.symbol,
+    ] {
+        match dic {
+            Some(path) => hasher
+                .update(std::fs::read(path).context(format!("Failed to read {}", path.display()))?),
+            None => hasher.update([0]),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn generation() -> u64 {
+    dictionaries().generation.load(Ordering::Acquire)
+}
+
+/// Marks the dictionaries as changed on disk. Workers reload them before their
+/// next job.
+pub fn reload() -> Result<()> {
+    crate::cache::set_dictionary_version(version()?);
+    dictionaries().generation.fetch_add(1, Ordering::AcqRel);
+
+    Ok(())
+}
+
+/// Loads the dictionaries into an engine instance.
+pub fn load(aitalked: &Aitalked) -> Result<()> {
+    let dictionaries = dictionaries();
+
+    if let Some(word_dic) = &dictionaries.word {
+        let code = unsafe { aitalked.reload_word_dic(Some(&path_to_sjis_cstring(word_dic))) };
+
+        if code != ResultCode::SUCCESS {
+            anyhow::bail!("Failed to aitalked.reload_word_dic {code:?}");
+        }
+    }
+
+    if let Some(phrase_dic) = &dictionaries.phrase {
+        let code = unsafe { aitalked.reload_phrase_dic(Some(&path_to_sjis_cstring(phrase_dic))) };
+
+        if code != ResultCode::SUCCESS {
+            anyhow::bail!("Failed to aitalked.reload_phrase_dic {code:?}");
+        }
+    }
+
+    if let Some(symbol_dic) = &dictionaries.symbol {
+        let code = unsafe { aitalked.reload_symbol_dic(Some(&path_to_sjis_cstring(symbol_dic))) };
+
+        if code != ResultCode::SUCCESS {
+            anyhow::bail!("Failed to aitalked.reload_symbol_dic {code:?}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Dubbing: subtitles spoken at their start times in a single track.
+
+use anyhow::Result;
+use axum::http::StatusCode;
+
+use crate::audio::{SAMPLE_RATE, ms_to_samples, stretch, wav::Cue, wav::Wav};
+use crate::limits::{Limits, OutputLimit, SecondsPolicy, TooLong};
+use crate::model::{ApiRequest, DubAdjustment, DubRequest, Request};
+use crate::synthesis::{Rejection, Workers};
+use crate::text::srt::Subtitle;
+
+/// Fade applied where overrunning speech is cut.
+const FADE_OUT_MS: u32 = 50;
+
+/// Most subtitles in a dub, all of which are queued at once.
+const MAX_SUBTITLES: usize = 2000;
+
+/// Longest track produced, whatever the limits.
+const MAX_TRACK_MS: u32 = 30 * 60 * 1000;
+
+pub struct Dub {
+    pub wav: Wav,
+    pub adjusted: Vec<DubAdjustment>,
+}
+
+fn subtitle_error(index: usize, rejection: Rejection) -> Rejection {
+    Rejection {
+        message: format!("Subtitle {}: {}", index + 1, rejection.message),
+        ..rejection
+    }
+}
+
+fn decode(voices: Vec<std::sync::Arc<Vec<u8>>>) -> Result<Vec<Vec<i16>>, Rejection> {
+    voices
+        .iter()
+        .map(|voice| Wav::decode(voice).map(|wav| wav.to_mono()))
+        .collect::<Result<_>>()
+        .map_err(|e| Rejection::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))
+}
+
+fn to_ms(samples: usize) -> u64 {
+    samples as u64 * 1000 / SAMPLE_RATE as u64
+}
+
+/// Synthesizes every subtitle and places it at its start time. Speech that
+/// would run into the next subtitle is time-stretched to fit, up to
+/// `max_speed` (from 1.0 to 10.0), and cut with a fade beyond that.
+pub async fn synthesize(
+    workers: &Workers,
+    request: DubRequest,
+    limits: &Limits,
+) -> Result<Dub, Rejection> {
+    let subtitles = crate::text::srt::parse(&request.srt)
+        .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, e))?;
+
+    if subtitles.is_empty() {
+        return Err(Rejection::new(StatusCode::BAD_REQUEST, "No subtitles"));
+    }
+
+    if subtitles.len() > MAX_SUBTITLES {
+        return Err(Rejection::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "{} subtitles, at most {MAX_SUBTITLES} are allowed",
+                subtitles.len()
+            ),
+        ));
+    }
+
+    let queue_key = request
+        .queue_key
+        .clone()
+        .unwrap_or_else(|| crate::synthesis::batch_key("dub"));
+
+    // Reject what cannot fit before synthesizing anything
+    let end_ms = subtitles
+        .iter()
+        .map(|subtitle| subtitle.end_ms)
+        .max()
+        .unwrap();
+    let max_ms = match limits.max_seconds {
+        Some(max_seconds) if limits.seconds_policy == SecondsPolicy::Reject => {
+            ((max_seconds * 1000.0) as u64).min(MAX_TRACK_MS as u64)
+        }
+        _ => MAX_TRACK_MS as u64,
+    };
+
+    if end_ms > max_ms {
+        return Err(Rejection::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Subtitles run for {end_ms} ms, the limit is {max_ms} ms"),
+        ));
+    }
+
+    let mut api_reqs = vec![];
+
+    for (index, subtitle) in subtitles.iter().enumerate() {
+        let mut api_req = ApiRequest {
+            is_kansai: request.is_kansai,
+            deadline_ms: request.deadline_ms,
+            max_queue_wait_ms: request.max_queue_wait_ms,
+            queue_key: Some(queue_key.clone()),
+            adaptive_speed: false,
+            body: Request {
+                speed: request.speed,
+                pitch: request.pitch,
+                volume: request.volume,
+                ruby: request.ruby,
+                trim_silence: true,
+                ..Request::new(&request.voice_id, &subtitle.text)
+            },
+        };
+
+        limits
+            .apply(&mut api_req.body)
+            .map_err(|e| subtitle_error(index, Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, e)))?;
+        crate::synthesis::validate(&api_req).map_err(|e| subtitle_error(index, e))?;
+
+        api_reqs.push(api_req);
+    }
+
+    workers.admit(&api_reqs)?;
+
+    let voices = workers
+        .synthesize_all(api_reqs)
+        .await
+        .map_err(|(index, rejection)| subtitle_error(index, rejection))?;
+
+    let speech = decode(voices)?;
+    let max_speed = request.max_speed.clamp(1.0, stretch::MAX_SPEED);
+    let limit = limits.output_limit();
+
+    tokio::task::spawn_blocking(move || place(&subtitles, speech, max_speed, limit))
+        .await
+        .unwrap()
+        .map_err(|e| Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, e))
+}
+
+/// Fits every clip before the next subtitle and mixes them at their start
+/// times, holding the track to `limit`.
+fn place(
+    subtitles: &[Subtitle],
+    mut speech: Vec<Vec<i16>>,
+    max_speed: f32,
+    limit: Option<OutputLimit>,
+) -> Result<Dub, TooLong> {
+    let starts: Vec<_> = subtitles
+        .iter()
+        .map(|subtitle| ms_to_samples(subtitle.start_ms as u32))
+        .collect();
+
+    let mut adjusted = vec![];
+
+    // Room until the next subtitle starts; the last one may run on
+    for (index, slot) in starts.windows(2).map(|pair| pair[1] - pair[0]).enumerate() {
+        let samples = &mut speech[index];
+        let length = samples.len();
+
+        if length <= slot || slot == 0 {
+            continue;
+        }
+
+        let factor = length as f32 / slot as f32;
+        let speed = factor.min(max_speed);
+
+        stretch::apply(samples, speed, 1.0);
+        let truncated = samples.len() > slot;
+
+        OutputLimit {
+            max_samples: slot,
+            fade_out: Some(ms_to_samples(FADE_OUT_MS)),
+        }
+        .enforce(samples, &mut vec![])?;
+
+        adjusted.push(DubAdjustment {
+            index: index + 1,
+            start_ms: subtitles[index].start_ms,
+            end_ms: subtitles[index].end_ms,
+            speech_ms: to_ms(length),
+            slot_ms: to_ms(slot),
+            speed,
+            truncated,
+        });
+    }
+
+    let length = starts
+        .iter()
+        .zip(&speech)
+        .map(|(start, samples)| start + samples.len())
+        .chain([ms_to_samples(subtitles.last().unwrap().end_ms as u32)])
+        .max()
+        .unwrap()
+        .min(ms_to_samples(MAX_TRACK_MS));
+
+    let mut track = vec![0i16; length];
+    let mut cues = vec![];
+
+    for ((start, samples), subtitle) in starts.into_iter().zip(speech).zip(subtitles) {
+        for (dest, s) in track[start..].iter_mut().zip(samples) {
+            *dest = dest.saturating_add(s);
+        }
+
+        cues.push(Cue {
+            position: start as u32,
+            label: subtitle.text.clone(),
+        });
+    }
+
+    if let Some(limit) = limit {
+        limit.enforce(&mut track, &mut cues)?;
+    }
+
+    Ok(Dub {
+        wav: Wav {
+            cues,
+            ..Wav::mono(track)
+        },
+        adjusted,
+    })
+}
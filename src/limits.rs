@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{SAMPLE_RATE, ms_to_samples, wav::Cue};
+use crate::model::Request;
+use crate::text::Segment;
+
+static LIMITS: OnceCell<(Limits, HashMap<String, Limits>)> = OnceCell::new();
+
+fn default_truncate_suffix() -> String {
+    "以下略".to_string()
+}
+
+fn default_fade_out_ms() -> u32 {
+    500
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum CharsPolicy {
+    /// Answer 413
+    #[default]
+    Reject,
+
+    /// Cut the text and append `truncate_suffix`
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SecondsPolicy {
+    /// Answer 413
+    #[default]
+    Reject,
+
+    /// Cut the audio with a fade-out
+    FadeOut,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limits {
+    pub max_chars: Option<usize>,
+
+    #[serde(default)]
+    pub chars_policy: CharsPolicy,
+
+    #[serde(default = "default_truncate_suffix")]
+    pub truncate_suffix: String,
+
+    pub max_seconds: Option<f32>,
+
+    #[serde(default)]
+    pub seconds_policy: SecondsPolicy,
+
+    #[serde(default = "default_fade_out_ms")]
+    pub fade_out_ms: u32,
+}
+
+/// The output length limit of a job, enforced by the worker on the final
+/// audio. Part of the cache key, as it changes the output.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputLimit {
+    pub max_samples: usize,
+
+    /// Samples faded out before the cut, or `None` to reject longer outputs.
+    pub fade_out: Option<usize>,
+}
+
+/// Output longer than its [`OutputLimit`] allows.
+#[derive(Debug)]
+pub struct TooLong;
+
+impl std::fmt::Display for TooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Synthesized speech exceeds the duration limit")
+    }
+}
+
+impl std::error::Error for TooLong {}
+
+/// Sets the server-wide limits and loads the per-API-key ones, a JSON object
+/// mapping keys to limits that replace the server-wide ones.
+pub fn init(server: Limits, per_key: Option<&Path>) -> Result<()> {
+    let per_key = match per_key {
+        Some(path) => {
+            serde_json::from_slice(&std::fs::read(path).context("Failed to read API key limits")?)
+                .context("Failed to parse API key limits")?
+        }
+        None => HashMap::new(),
+    };
+
+    LIMITS.get_or_init(|| (server, per_key));
+
+    Ok(())
+}
+
+/// Limits of `api_key`, or the server-wide ones for unknown or missing keys.
+pub fn get(api_key: Option<&str>) -> &'static Limits {
+    let (server, per_key) = LIMITS.get().unwrap();
+
+    api_key
+        .and_then(|api_key| per_key.get(api_key))
+        .unwrap_or(server)
+}
+
+/// Characters spoken, not counting markup.
+fn spoken_chars(body: &Request) -> usize {
+    match &body.segments {
+        Some(segments) => segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Speech { text, .. } => text.chars().count(),
+                Segment::Break { .. } => 0,
+            })
+            .sum(),
+        None => body.text.chars().count(),
+    }
+}
+
+/// Keeps the first `keep` spoken characters and appends `suffix`.
+fn truncate(body: &mut Request, keep: usize, suffix: &str) {
+    let Some(segments) = &mut body.segments else {
+        body.text = body.text.chars().take(keep).collect();
+        body.text.push_str(suffix);
+        return;
+    };
+
+    let mut remaining = keep;
+
+    for (i, segment) in segments.iter_mut().enumerate() {
+        let Segment::Speech { text, .. } = segment else {
+            continue;
+        };
+
+        let chars = text.chars().count();
+
+        if chars > remaining {
+            *text = text.chars().take(remaining).collect();
+            text.push_str(suffix);
+            segments.truncate(i + 1);
+            return;
+        }
+
+        remaining -= chars;
+    }
+}
+
+impl Limits {
+    /// Enforces the text length limit and attaches the output limit. Errors
+    /// when the request has to be rejected.
+    pub fn apply(&self, body: &mut Request) -> Result<(), String> {
+        if let Some(max_chars) = self.max_chars {
+            let chars = spoken_chars(body);
+
+            if chars > max_chars {
+                match self.chars_policy {
+                    CharsPolicy::Reject => {
+                        return Err(format!(
+                            "Text has {chars} characters, the limit is {max_chars}"
+                        ));
+                    }
+                    CharsPolicy::Truncate => {
+                        let keep = max_chars.saturating_sub(self.truncate_suffix.chars().count());
+                        truncate(body, keep, &self.truncate_suffix);
+                    }
+                }
+            }
+        }
+
+        body.output_limit = self.output_limit();
+
+        Ok(())
+    }
+
+    /// The output length limit, if any.
+    pub fn output_limit(&self) -> Option<OutputLimit> {
+        self.max_seconds.map(|max_seconds| OutputLimit {
+            max_samples: (max_seconds * SAMPLE_RATE as f32) as usize,
+            fade_out: (self.seconds_policy == SecondsPolicy::FadeOut)
+                .then(|| ms_to_samples(self.fade_out_ms)),
+        })
+    }
+}
+
+impl OutputLimit {
+    /// Cuts `samples` (and the cues past the cut) down to the limit.
+    pub fn enforce(&self, samples: &mut Vec<i16>, cues: &mut Vec<Cue>) -> Result<(), TooLong> {
+        if samples.len() <= self.max_samples {
+            return Ok(());
+        }
+
+        let Some(fade_out) = self.fade_out else {
+            return Err(TooLong);
+        };
+
+        samples.truncate(self.max_samples);
+        cues.retain(|cue| (cue.position as usize) < self.max_samples);
+
+        let fade_out = fade_out.min(samples.len());
+        let start = samples.len() - fade_out;
+
+        for (i, s) in samples[start..].iter_mut().enumerate() {
+            let gain = 1.0 - (i + 1) as f32 / fade_out as f32;
+            *s = (*s as f32 * gain) as i16;
+        }
+
+        Ok(())
+    }
+}
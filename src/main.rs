@@ -1,12 +1,32 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use tokio::sync::{mpsc, oneshot};
+use directories::ProjectDirs;
 
+use scheduler::Scheduler;
+use tokio::sync::oneshot;
+
+mod adaptive;
+mod assets;
+mod audio;
+mod audiobook;
+mod cache;
+mod coalesce;
+mod dialogue;
+mod dictionary;
+mod dub;
+mod limits;
+mod metrics;
 mod model;
+mod scheduler;
+mod synthesis;
+mod text;
 mod voices;
+mod warmup;
 mod web;
 mod worker;
 
@@ -33,6 +53,130 @@ struct Cli {
 
     #[arg(long, env)]
     symbol_dic: Option<PathBuf>,
+
+    #[arg(long, env)]
+    effect_presets: Option<PathBuf>,
+
+    /// Embeds a watermark carrying this ID into every synthesized clip
+    #[arg(long, env)]
+    watermark_id: Option<u16>,
+
+    #[arg(long, env, default_value = "aitalked-server")]
+    watermark_key: String,
+
+    #[arg(long, env, default_value = "0.03")]
+    watermark_strength: f32,
+
+    /// Directory holding background assets (defaults to the user data directory)
+    #[arg(long, env)]
+    asset_dir: Option<PathBuf>,
+
+    /// Directory audiobooks are written to (defaults to the user data directory)
+    #[arg(long, env)]
+    audiobook_dir: Option<PathBuf>,
+
+    /// Bearer token for /api/admin endpoints; they are disabled when unset
+    #[arg(long, env)]
+    admin_token: Option<String>,
+
+    /// Size limit of the in-memory result cache in MiB (0 disables it)
+    #[arg(long, env, default_value = "64")]
+    cache_memory_mb: u64,
+
+    /// Size limit of the on-disk result cache in MiB (0 disables it)
+    #[arg(long, env, default_value = "0")]
+    cache_disk_mb: u64,
+
+    /// Directory of the on-disk result cache (defaults to the user cache directory)
+    #[arg(long, env)]
+    cache_dir: Option<PathBuf>,
+
+    /// Size limit of the text analysis (AIKANA) cache of each worker in MiB
+    #[arg(long, env, default_value = "8")]
+    kana_cache_mb: u64,
+
+    /// Texts longer than this many characters are synthesized sentence by
+    /// sentence in chunks of at most this size
+    #[arg(long, env, default_value = "200")]
+    max_chunk_chars: usize,
+
+    /// Size limit of the cache of chunk audio of each worker in MiB
+    #[arg(long, env, default_value = "32")]
+    chunk_cache_mb: u64,
+
+    /// Longest text accepted, in characters
+    #[arg(long, env)]
+    max_chars: Option<usize>,
+
+    /// What to do with longer texts
+    #[arg(long, env, value_enum, default_value = "reject")]
+    chars_policy: limits::CharsPolicy,
+
+    /// Appended to texts cut by the truncate policy
+    #[arg(long, env, default_value = "以下略")]
+    truncate_suffix: String,
+
+    /// Longest output accepted, in seconds
+    #[arg(long, env)]
+    max_seconds: Option<f32>,
+
+    /// What to do with longer outputs
+    #[arg(long, env, value_enum, default_value = "reject")]
+    seconds_policy: limits::SecondsPolicy,
+
+    /// Length of the fade-out of the fade-out policy
+    #[arg(long, env, default_value = "500")]
+    fade_out_ms: u32,
+
+    /// JSON object mapping X-Api-Key values to limits replacing the above
+    #[arg(long, env)]
+    api_key_limits: Option<PathBuf>,
+
+    /// Jobs of one queue_key (times its weight) waiting per worker before its
+    /// requests are answered with 429
+    #[arg(long, env, default_value = "16")]
+    queue_length: usize,
+
+    /// Weight of a queue_key in the round-robin between keys, as KEY=WEIGHT
+    /// (keys default to 1)
+    #[arg(long, env, value_parser = parse_queue_weight, value_delimiter = ',')]
+    queue_weight: Vec<(String, u32)>,
+
+    /// Pending jobs of a queue_key from which adaptive_speed requests speed up
+    #[arg(long, env, default_value = "3")]
+    adaptive_threshold: usize,
+
+    /// Speed factor added per pending job from the threshold on
+    #[arg(long, env, default_value = "0.1")]
+    adaptive_step: f32,
+
+    /// Upper limit of the speed raised by adaptive_speed
+    #[arg(long, env, default_value = "2.0")]
+    adaptive_max_speed: f32,
+
+    /// Also shorten pauses by the adaptive speed factor
+    #[arg(long, env)]
+    adaptive_shorten_pauses: bool,
+
+    /// Spoken once by every voice at startup; empty to skip the warm-up
+    #[arg(long, env, default_value = "こんにちは")]
+    warmup_text: String,
+
+    /// JSON array of /api/tts requests synthesized into the cache at startup
+    #[arg(long, env)]
+    phrase_bank: Option<PathBuf>,
+}
+
+fn parse_queue_weight(s: &str) -> Result<(String, u32), String> {
+    let (key, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{s} is not KEY=WEIGHT"))?;
+
+    let weight = weight
+        .parse()
+        .map_err(|e| format!("Invalid weight of {key}: {e}"))?;
+
+    Ok((key.to_string(), weight))
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -40,6 +184,79 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt().with_thread_names(true).init();
 
     let cli = Cli::parse();
+
+    let project_dirs = ProjectDirs::from("", "", "aitalked-server");
+
+    let asset_dir = cli
+        .asset_dir
+        .clone()
+        .or_else(|| project_dirs.as_ref().map(|d| d.data_dir().join("assets")))
+        .context("Failed to find a data directory, specify --asset-dir")?;
+
+    let audiobook_dir = cli
+        .audiobook_dir
+        .clone()
+        .or_else(|| project_dirs.as_ref().map(|d| d.data_dir().join("audiobooks")))
+        .context("Failed to find a data directory, specify --audiobook-dir")?;
+
+    audio::effects::init_presets(cli.effect_presets.as_deref())
+        .expect("Failed to init effect presets");
+
+    audio::watermark::init(&cli.watermark_key, cli.watermark_id, cli.watermark_strength);
+
+    assets::init(&asset_dir).expect("Failed to init assets");
+
+    audiobook::init(&audiobook_dir).expect("Failed to init audiobooks");
+
+    limits::init(
+        limits::Limits {
+            max_chars: cli.max_chars,
+            chars_policy: cli.chars_policy,
+            truncate_suffix: cli.truncate_suffix.clone(),
+            max_seconds: cli.max_seconds,
+            seconds_policy: cli.seconds_policy,
+            fade_out_ms: cli.fade_out_ms,
+        },
+        cli.api_key_limits.as_deref(),
+    )
+    .expect("Failed to init limits");
+
+    adaptive::init(adaptive::Policy {
+        threshold: cli.adaptive_threshold,
+        step: cli.adaptive_step,
+        max_speed: cli.adaptive_max_speed,
+        shorten_pauses: cli.adaptive_shorten_pauses,
+    });
+
+    let cache_dir = match cli.cache_disk_mb {
+        0 => None,
+        _ => Some(
+            cli.cache_dir
+                .clone()
+                .or_else(|| project_dirs.as_ref().map(|d| d.cache_dir().join("results")))
+                .context("Failed to find a cache directory, specify --cache-dir")?,
+        ),
+    };
+
+    // The engine resolves relative paths from the installation directory
+    let dic_path = |dic: &Option<PathBuf>| dic.as_ref().map(|dic| cli.installation_dir.join(dic));
+
+    dictionary::init(
+        dic_path(&cli.word_dic),
+        dic_path(&cli.phrase_dic),
+        dic_path(&cli.symbol_dic),
+    );
+
+    cache::init(
+        cli.cache_memory_mb * 1024 * 1024,
+        cache_dir
+            .as_deref()
+            .map(|dir| (dir, cli.cache_disk_mb * 1024 * 1024)),
+        dictionary::version().expect("Failed to read dictionaries"),
+        cli.max_chunk_chars,
+    )
+    .expect("Failed to init cache");
+
     std::env::set_current_dir(&cli.installation_dir).unwrap();
 
     let listen = cli.listen;
@@ -48,9 +265,18 @@ async fn main() -> Result<()> {
         .await
         .with_context(|| format!("Failed to bind address {listen}"))?;
 
-    let (tx_kansai, rx_kansai) = mpsc::channel(1);
+    let engine_config = worker::EngineConfig {
+        kana_cache_bytes: cli.kana_cache_mb * 1024 * 1024,
+        chunk_cache_bytes: cli.chunk_cache_mb * 1024 * 1024,
+        max_chunk_chars: cli.max_chunk_chars,
+    };
+
+    let queue_weights: HashMap<_, _> = cli.queue_weight.iter().cloned().collect();
+    let scheduler_kansai = Arc::new(Scheduler::new(cli.queue_length, queue_weights.clone()));
     let (tx_kansai_result, rx_kansai_result) = oneshot::channel();
-    let (tx, rx) = mpsc::channel(1);
+    let scheduler = Arc::new(Scheduler::new(cli.queue_length, queue_weights));
+    let stats_kansai = Arc::new(worker::Stats::default());
+    let stats = Arc::new(worker::Stats::default());
     let (tx_result, rx_result) = oneshot::channel();
     let (tx_icon_result, rx_icon_result) = oneshot::channel();
 
@@ -58,18 +284,23 @@ async fn main() -> Result<()> {
         .name("WkrStdKnsi".to_string())
         .spawn({
             let cli = cli.clone();
+            let scheduler_kansai = scheduler_kansai.clone();
+            let stats_kansai = stats_kansai.clone();
             move || match worker::initialization(
                 &cli.installation_dir,
                 "aitalked_kansai.dll",
                 "Lang\\standard_kansai",
-                cli.word_dic.as_deref(),
-                cli.phrase_dic.as_deref(),
-                cli.symbol_dic.as_deref(),
                 &cli.auth_seed,
             ) {
                 Ok((aitalked, param)) => {
                     tx_kansai_result.send(Ok(())).unwrap();
-                    worker::event_loop(aitalked, param, rx_kansai);
+                    worker::event_loop(
+                        aitalked,
+                        param,
+                        scheduler_kansai,
+                        engine_config,
+                        stats_kansai,
+                    );
                 }
                 Err(e) => {
                     tx_kansai_result.send(Err(e)).unwrap();
@@ -82,18 +313,23 @@ async fn main() -> Result<()> {
         .name("WkrStd".to_string())
         .spawn({
             let cli = cli.clone();
+            let scheduler = scheduler.clone();
+            let stats = stats.clone();
             move || match worker::initialization(
                 &cli.installation_dir,
                 "aitalked.dll",
                 "Lang\\standard",
-                cli.word_dic.as_deref(),
-                cli.phrase_dic.as_deref(),
-                cli.symbol_dic.as_deref(),
                 &cli.auth_seed,
             ) {
                 Ok((aitalked, param)) => {
                     tx_result.send(Ok(())).unwrap();
-                    worker::event_loop(aitalked, param, rx);
+                    worker::event_loop(
+                        aitalked,
+                        param,
+                        scheduler,
+                        engine_config,
+                        stats,
+                    );
                 }
                 Err(e) => {
                     tx_result.send(Err(e)).unwrap();
@@ -126,8 +362,23 @@ async fn main() -> Result<()> {
         .unwrap()
         .expect("Failed to init worker standard_kansai");
 
+    let workers = synthesis::Workers {
+        standard: synthesis::Queue::new(scheduler, stats),
+        kansai: synthesis::Queue::new(scheduler_kansai, stats_kansai),
+    };
+
+    warmup::warm_up(&workers, &cli.warmup_text).await;
+
+    if let Some(phrase_bank) = &cli.phrase_bank {
+        warmup::fill_phrase_bank(&workers, phrase_bank)
+            .await
+            .expect("Failed to fill phrase bank");
+    }
+
     tracing::info!("Ready to use");
-    web::serve(listener, tx, tx_kansai).await.unwrap();
+    web::serve(listener, workers, cli.admin_token)
+        .await
+        .unwrap();
 
     Ok(())
 }
@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::model::Metrics;
+
+pub static TTS_REQUESTS: AtomicU64 = AtomicU64::new(0);
+pub static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static COALESCED: AtomicU64 = AtomicU64::new(0);
+pub static SYNTHESES: AtomicU64 = AtomicU64::new(0);
+pub static REJECTED: AtomicU64 = AtomicU64::new(0);
+pub static EXPIRED: AtomicU64 = AtomicU64::new(0);
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> Metrics {
+    Metrics {
+        tts_requests: TTS_REQUESTS.load(Ordering::Relaxed),
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+        syntheses: SYNTHESES.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+        expired: EXPIRED.load(Ordering::Relaxed),
+    }
+}
@@ -1,7 +1,14 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 
+use crate::audio::{effects::Effect, mix::Background};
+use crate::limits::OutputLimit;
+use crate::text::{Segment, TextFormat};
+
 fn default_pause_sentence() -> i32 {
     800
 }
@@ -30,19 +37,55 @@ fn default_range() -> f32 {
     1.0
 }
 
+fn default_post_speed() -> f32 {
+    1.0
+}
+
+fn default_post_pitch() -> f32 {
+    1.0
+}
+
+fn default_silence_threshold() -> f32 {
+    -50.0
+}
+
+fn default_gap_ms() -> u32 {
+    300
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ApiRequest {
     pub is_kansai: Option<bool>,
 
+    /// UNIX time in milliseconds after which the result is no longer wanted.
+    pub deadline_ms: Option<u64>,
+
+    /// How long the job may wait for a worker.
+    pub max_queue_wait_ms: Option<u64>,
+
+    /// Jobs of different keys (e.g. guild IDs) take turns on the workers.
+    pub queue_key: Option<String>,
+
+    /// Speak faster while `queue_key` has a backlog.
+    #[serde(default)]
+    pub adaptive_speed: bool,
+
     #[serde(flatten)]
     pub body: Request,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     pub voice_id: String,
     pub text: String,
 
+    #[serde(default)]
+    pub text_format: TextFormat,
+
+    /// Read `｜漢字《かんじ》` and `<ruby>` as reading hints.
+    #[serde(default)]
+    pub ruby: bool,
+
     #[serde(default = "default_volume")]
     pub volume: f32,
 
@@ -63,14 +106,310 @@ pub struct Request {
 
     #[serde(default = "default_pause_sentence")]
     pub pause_sentence: i32,
+
+    #[serde(default = "default_post_speed")]
+    pub post_speed: f32,
+
+    #[serde(default = "default_post_pitch")]
+    pub post_pitch: f32,
+
+    #[serde(default)]
+    pub trim_silence: bool,
+
+    #[serde(default = "default_silence_threshold")]
+    pub silence_threshold: f32,
+
+    pub leading_padding_ms: Option<u32>,
+
+    pub trailing_padding_ms: Option<u32>,
+
+    #[serde(default)]
+    pub metadata: bool,
+
+    pub effect_preset: Option<String>,
+
+    #[serde(default)]
+    pub effects: Vec<Effect>,
+
+    pub background: Option<Background>,
+
+    /// Set from the limits of the API key, never by the client.
+    #[serde(skip_deserializing)]
+    pub output_limit: Option<OutputLimit>,
+
+    /// `text` parsed according to `text_format`, `None` for plain text.
+    #[serde(skip_deserializing)]
+    pub segments: Option<Vec<Segment>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DialogueRequest {
+    #[serde(default)]
+    pub lines: Vec<DialogueLine>,
+
+    /// Text split into lines of the narrator and dialogue voices, instead of
+    /// `lines`.
+    pub novel: Option<Novel>,
+
+    /// Silence after each line unless the line sets its own.
+    #[serde(default = "default_gap_ms")]
+    pub gap_ms: u32,
+
+    /// Stereo position per voice ID, from -1.0 (left) to 1.0 (right). The
+    /// output is stereo when set; voices not listed stay centered.
+    #[serde(default)]
+    pub pan: HashMap<String, f32>,
+
+    /// Answer a [`DialogueTiming`] instead of the bare WAV.
+    #[serde(default)]
+    pub timing: bool,
+
+    /// Answer a ZIP of the mix, a stem per voice and the timeline.
+    #[serde(default)]
+    pub stems: bool,
+
+    pub deadline_ms: Option<u64>,
+    pub max_queue_wait_ms: Option<u64>,
+    pub queue_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Novel {
+    pub text: String,
+    pub narrator: String,
+
+    /// Voice of quotes without a known speaker, the narrator if unset.
+    pub dialogue: Option<String>,
+
+    /// Voice ID per speaker name written before quotes (`名前「...」`).
+    #[serde(default)]
+    pub speakers: HashMap<String, String>,
+
+    #[serde(default)]
+    pub ruby: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DialogueLine {
+    pub is_kansai: Option<bool>,
+    pub gap_ms: Option<u32>,
+
+    #[serde(flatten)]
+    pub body: Request,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LineTiming {
+    pub voice_id: String,
+    pub text: String,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DialogueTiming {
+    /// Base64 of the WAV.
+    pub audio: String,
+    pub lines: Vec<LineTiming>,
+}
+
+fn default_max_speed() -> f32 {
+    2.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DubRequest {
+    /// SubRip subtitles.
+    pub srt: String,
+    pub voice_id: String,
+    pub is_kansai: Option<bool>,
+
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+
+    #[serde(default = "default_pitch")]
+    pub pitch: f32,
+
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+
+    #[serde(default)]
+    pub ruby: bool,
+
+    /// Speech is sped up at most this much to end before the next subtitle,
+    /// and cut beyond that.
+    #[serde(default = "default_max_speed")]
+    pub max_speed: f32,
+
+    pub deadline_ms: Option<u64>,
+    pub max_queue_wait_ms: Option<u64>,
+    pub queue_key: Option<String>,
+}
+
+/// A subtitle whose speech had to be sped up or cut to fit.
+#[derive(Debug, Serialize)]
+pub struct DubAdjustment {
+    /// Position of the subtitle, from 1, in start time order.
+    pub index: usize,
+    pub start_ms: u64,
+    pub end_ms: u64,
+
+    /// Length of the speech at the requested speed.
+    pub speech_ms: u64,
+
+    /// Time until the next subtitle starts.
+    pub slot_ms: u64,
+
+    pub speed: f32,
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DubReport {
+    /// Base64 of the WAV.
+    pub audio: String,
+    pub adjusted: Vec<DubAdjustment>,
+}
+
+/// Query of an audiobook upload.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudiobookParams {
+    pub voice_id: String,
+    pub is_kansai: Option<bool>,
+
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+
+    #[serde(default)]
+    pub ruby: bool,
+
+    /// Also join the chapters into a single file.
+    #[serde(default)]
+    pub single_file: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudiobookState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterStatus {
+    pub title: String,
+    pub characters: usize,
+
+    /// Set once the chapter is synthesized.
+    pub file: Option<String>,
+    pub duration: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudiobookStatus {
+    pub id: String,
+    pub title: Option<String>,
+    pub voice_id: String,
+    pub state: AudiobookState,
+
+    /// Share of the characters synthesized, from 0.0 to 1.0.
+    pub progress: f32,
+    pub error: Option<String>,
+    pub chapters: Vec<ChapterStatus>,
+
+    /// The whole book, when requested and done.
+    pub file: Option<String>,
+}
+
+impl ApiRequest {
+    /// The earlier of `deadline_ms` and `max_queue_wait_ms`, counted from now.
+    pub fn deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+
+        let deadline = self.deadline_ms.map(|deadline_ms| {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+
+            now + Duration::from_millis(deadline_ms.saturating_sub(now_ms))
+        });
+
+        let max_queue_wait = self
+            .max_queue_wait_ms
+            .map(|ms| now + Duration::from_millis(ms));
+
+        deadline.into_iter().chain(max_queue_wait).min()
+    }
+}
+
+impl Request {
+    /// A request with every parameter at its default.
+    pub fn new(voice_id: &str, text: &str) -> Self {
+        serde_json::from_value(serde_json::json!({ "voice_id": voice_id, "text": text })).unwrap()
+    }
 }
 
 #[derive(Debug)]
 pub struct RequestContext {
     pub body: Request,
+
+    /// The worker drops the job instead of starting it after this.
+    pub deadline: Option<Instant>,
     pub channel: oneshot::Sender<Result<Vec<u8>>>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct Asset {
+    pub name: String,
+    pub duration: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub voice_id: String,
+    pub text_length: usize,
+    pub queue_key: String,
+
+    /// Running time of a running job, waiting time of a queued one.
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub running: Option<JobStatus>,
+    pub queued: Vec<JobStatus>,
+    pub completed: u64,
+    pub average_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    pub workers: Vec<WorkerStatus>,
+    pub metrics: Metrics,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Metrics {
+    pub tts_requests: u64,
+    pub cache_hits: u64,
+    pub coalesced: u64,
+    pub syntheses: u64,
+    pub rejected: u64,
+    pub expired: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatermarkDetection {
+    pub detected: bool,
+    pub server_id: Option<u16>,
+    pub timestamp: Option<u32>,
+    pub confidence: Option<f32>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Voice {
     pub id: String,
@@ -0,0 +1,225 @@
+//! Job queue of a worker, fair between `queue_key`s.
+//!
+//! Keys with pending jobs take turns in weighted round-robin: a key with
+//! weight N gets up to N jobs started before the next key's turn. Jobs of the
+//! same key keep their FIFO order. The capacity applies to each key (times its
+//! weight), so a busy key cannot crowd the others out of the queue.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use crate::model::{JobStatus, RequestContext};
+
+struct Job {
+    ctx: RequestContext,
+    queue_key: String,
+    enqueued_at: Instant,
+}
+
+impl Job {
+    fn status(&self, since: Instant) -> JobStatus {
+        JobStatus {
+            voice_id: self.ctx.body.voice_id.clone(),
+            text_length: self.ctx.body.text.chars().count(),
+            queue_key: self.queue_key.clone(),
+            elapsed_ms: since.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<String, VecDeque<Job>>,
+
+    /// Keys with pending jobs, the one being served first.
+    turns: VecDeque<String>,
+
+    /// Jobs started for the front key during its current turn.
+    served: u32,
+
+    len: usize,
+
+    running: Option<(JobStatus, Instant)>,
+}
+
+pub struct Scheduler {
+    state: Mutex<State>,
+    available: Condvar,
+    capacity: usize,
+    weights: HashMap<String, u32>,
+}
+
+impl Scheduler {
+    pub fn new(capacity: usize, weights: HashMap<String, u32>) -> Self {
+        Self {
+            state: Mutex::default(),
+            available: Condvar::new(),
+            capacity,
+            weights,
+        }
+    }
+
+    fn weight(&self, key: &str) -> u32 {
+        self.weights.get(key).copied().unwrap_or(1).max(1)
+    }
+
+    /// Jobs waiting, not counting the running one.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().len
+    }
+
+    /// Jobs of `key` that may wait at once.
+    fn capacity_of(&self, key: &str) -> usize {
+        self.capacity * self.weight(key) as usize
+    }
+
+    /// Whether [`Scheduler::try_push`] would refuse a job of `key`.
+    pub fn is_full(&self, key: &str) -> bool {
+        self.len_of(key) >= self.capacity_of(key)
+    }
+
+    /// Jobs of `key` waiting.
+    pub fn len_of(&self, key: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .queues
+            .get(key)
+            .map_or(0, VecDeque::len)
+    }
+
+    fn enqueue(&self, state: &mut State, key: &str, ctx: RequestContext) {
+        let queue = state.queues.entry(key.to_string()).or_default();
+
+        queue.push_back(Job {
+            ctx,
+            queue_key: key.to_string(),
+            enqueued_at: Instant::now(),
+        });
+
+        if queue.len() == 1 {
+            state.turns.push_back(key.to_string());
+        }
+
+        state.len += 1;
+
+        self.available.notify_one();
+    }
+
+    /// Queues a job regardless of the capacity.
+    pub fn push(&self, key: &str, ctx: RequestContext) {
+        self.enqueue(&mut self.state.lock().unwrap(), key, ctx);
+    }
+
+    /// Queues a job unless `key` has as many waiting as it may. Returns
+    /// whether it was queued.
+    pub fn try_push(&self, key: &str, ctx: RequestContext) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.queues.get(key).map_or(0, VecDeque::len) >= self.capacity_of(key) {
+            return false;
+        }
+
+        self.enqueue(&mut state, key, ctx);
+
+        true
+    }
+
+    /// Blocks until a job is available and returns the next one in turn.
+    pub fn pop(&self) -> RequestContext {
+        let mut state = self
+            .available
+            .wait_while(self.state.lock().unwrap(), |state| state.len == 0)
+            .unwrap();
+
+        let key = state.turns.front().unwrap().clone();
+        let queue = state.queues.get_mut(&key).unwrap();
+        let job = queue.pop_front().unwrap();
+        let drained = queue.is_empty();
+
+        state.len -= 1;
+        state.served += 1;
+
+        if drained {
+            state.queues.remove(&key);
+            state.turns.pop_front();
+            state.served = 0;
+        } else if state.served >= self.weight(&key) {
+            state.turns.rotate_left(1);
+            state.served = 0;
+        }
+
+        tracing::debug!("Queue key {key:?} waited {:?}", job.enqueued_at.elapsed());
+
+        state.running = Some((job.status(Instant::now()), Instant::now()));
+
+        job.ctx
+    }
+
+    /// Marks the job returned by the last [`Scheduler::pop`] as done.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().running = None;
+    }
+
+    /// The running job (elapsed since it started) and the queued ones
+    /// (elapsed since they were queued), oldest first.
+    pub fn status(&self) -> (Option<JobStatus>, Vec<JobStatus>) {
+        let state = self.state.lock().unwrap();
+
+        let running = state
+            .running
+            .as_ref()
+            .map(|(status, started_at)| JobStatus {
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+                ..status.clone()
+            });
+
+        let mut queued: Vec<_> = state.queues.values().flatten().collect();
+        queued.sort_by_key(|job| job.enqueued_at);
+
+        (
+            running,
+            queued
+                .iter()
+                .map(|job| job.status(job.enqueued_at))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Request;
+
+    fn job() -> RequestContext {
+        RequestContext {
+            body: Request::new("akari_44", "テスト"),
+            deadline: None,
+            channel: tokio::sync::oneshot::channel().0,
+        }
+    }
+
+    #[test]
+    fn capacity_applies_per_key() {
+        let scheduler = Scheduler::new(2, HashMap::from([("heavy".to_string(), 2)]));
+
+        assert!(scheduler.try_push("noisy", job()));
+        assert!(scheduler.try_push("noisy", job()));
+        assert!(!scheduler.try_push("noisy", job()));
+        assert!(scheduler.is_full("noisy"));
+
+        // Other keys still get in
+        assert!(scheduler.try_push("quiet", job()));
+        assert!(!scheduler.is_full("quiet"));
+
+        // Weighted keys get a larger share
+        for _ in 0..4 {
+            assert!(scheduler.try_push("heavy", job()));
+        }
+        assert!(!scheduler.try_push("heavy", job()));
+
+        assert_eq!(scheduler.len(), 7);
+    }
+}
@@ -0,0 +1,416 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::coalesce::Flight;
+use crate::limits::{Limits, TooLong};
+use crate::model::{ApiRequest, Request, RequestContext, WorkerStatus};
+use crate::scheduler::Scheduler;
+use crate::text::{Prosody, Segment};
+use crate::worker::{Expired, Stats};
+
+/// Assumed processing time of a job before any has finished.
+const DEFAULT_JOB_TIME: Duration = Duration::from_secs(1);
+
+static BATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Why a request was not synthesized.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub message: String,
+
+    /// Seconds after which retrying is expected to succeed.
+    pub retry_after: Option<u64>,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+}
+
+/// The job queue in front of a worker thread.
+#[derive(Clone)]
+pub struct Queue {
+    scheduler: Arc<Scheduler>,
+    stats: Arc<Stats>,
+}
+
+impl Queue {
+    pub fn new(scheduler: Arc<Scheduler>, stats: Arc<Stats>) -> Self {
+        Self { scheduler, stats }
+    }
+
+    /// Jobs waiting for the worker, not counting the running one.
+    pub fn pending(&self) -> usize {
+        self.scheduler.len()
+    }
+
+    pub fn status(&self, name: &str) -> WorkerStatus {
+        let (running, queued) = self.scheduler.status();
+
+        WorkerStatus {
+            name: name.to_string(),
+            running,
+            queued,
+            completed: self.stats.completed(),
+            average_ms: self.stats.average().map(|d| d.as_millis() as u64),
+        }
+    }
+
+    /// Time until the worker would get through everything queued now.
+    fn estimate(&self) -> Duration {
+        let average = self.stats.average().unwrap_or(DEFAULT_JOB_TIME);
+        average * (self.pending() as u32 + 1)
+    }
+
+    fn full(&self) -> Rejection {
+        crate::metrics::increment(&crate::metrics::REJECTED);
+
+        Rejection {
+            retry_after: Some(self.estimate().as_secs_f32().ceil() as u64),
+            ..Rejection::new(StatusCode::TOO_MANY_REQUESTS, "Queue is full")
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Workers {
+    pub standard: Queue,
+    pub kansai: Queue,
+}
+
+/// Where a result came from, reported in the `X-Cache` header.
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    Hit,
+    Coalesced,
+    Miss,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Hit => "HIT",
+            Source::Coalesced => "COALESCED",
+            Source::Miss => "MISS",
+        }
+    }
+}
+
+/// Splits marked-up text into segments, unless already done.
+pub fn parse_text(body: &mut Request) -> Result<(), Rejection> {
+    if body.segments.is_none() {
+        body.segments = crate::text::parse(&body.text, body.text_format)
+            .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, e))?;
+    }
+
+    Ok(())
+}
+
+/// A `queue_key` of its own for a batch (e.g. `dialogue:3`) given none, so
+/// that it takes turns with other requests instead of holding up every
+/// request without a key.
+pub fn batch_key(kind: &str) -> String {
+    format!("{kind}:{}", BATCHES.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Aborts the tasks when dropped, e.g. when the client disconnects.
+struct AbortOnDrop<T>(Vec<JoinHandle<T>>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Parses the text and applies `limits`, as `/api/tts` does before the
+/// cache key is taken.
+pub fn prepare(body: &mut Request, limits: &Limits) -> Result<(), Rejection> {
+    parse_text(body)?;
+
+    limits
+        .apply(body)
+        .map_err(|e| Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, e))
+}
+
+/// Checks what can be checked before queueing and resolves the dialect.
+pub fn validate(api_req: &ApiRequest) -> Result<bool, Rejection> {
+    let voice_id = &api_req.body.voice_id;
+
+    let Some((_, info)) = crate::voices::get().get(voice_id) else {
+        return Err(Rejection::new(
+            StatusCode::BAD_REQUEST,
+            format!("{voice_id} is not loaded"),
+        ));
+    };
+
+    // Voices switched to within the text
+    for segment in api_req.body.segments.iter().flatten() {
+        if let Segment::Speech {
+            prosody:
+                Prosody {
+                    voice_id: Some(voice_id),
+                    ..
+                },
+            ..
+        } = segment
+            && !crate::voices::get().contains_key(voice_id)
+        {
+            return Err(Rejection::new(
+                StatusCode::BAD_REQUEST,
+                format!("{voice_id} is not loaded"),
+            ));
+        }
+    }
+
+    if let Some(preset) = &api_req.body.effect_preset
+        && crate::audio::effects::preset(preset).is_none()
+    {
+        return Err(Rejection::new(
+            StatusCode::BAD_REQUEST,
+            format!("Effect preset {preset} is not defined"),
+        ));
+    }
+
+    crate::audio::stretch::validate(api_req.body.post_speed, api_req.body.post_pitch)
+        .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, e))?;
+
+    crate::audio::effects::validate(&api_req.body.effects)
+        .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, e))?;
+
+    if let Some(background) = &api_req.body.background
+        && crate::assets::get(&background.asset).is_none()
+    {
+        return Err(Rejection::new(
+            StatusCode::BAD_REQUEST,
+            format!("Background asset {} is not uploaded", background.asset),
+        ));
+    }
+
+    Ok(api_req.is_kansai.unwrap_or(info.dialect == "Kansai"))
+}
+
+impl Workers {
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        vec![
+            self.standard.status("standard"),
+            self.kansai.status("kansai"),
+        ]
+    }
+
+    pub fn queue(&self, is_kansai: bool) -> &Queue {
+        if is_kansai {
+            &self.kansai
+        } else {
+            &self.standard
+        }
+    }
+
+    /// Sends a job under `queue_key` straight to a worker, bypassing the
+    /// cache and the queue length limit.
+    pub async fn dispatch(
+        &self,
+        body: Request,
+        is_kansai: bool,
+        queue_key: &str,
+    ) -> Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.queue(is_kansai).scheduler.push(
+            queue_key,
+            RequestContext {
+                body,
+                deadline: None,
+                channel: tx,
+            },
+        );
+
+        crate::metrics::increment(&crate::metrics::SYNTHESES);
+
+        rx.await.unwrap()
+    }
+
+    /// Queues a job under `queue_key`. With `bounded`, rejects it when the
+    /// queue is full.
+    pub async fn try_dispatch(
+        &self,
+        body: Request,
+        is_kansai: bool,
+        deadline: Option<Instant>,
+        queue_key: &str,
+        bounded: bool,
+    ) -> Result<Vec<u8>, Rejection> {
+        let queue = self.queue(is_kansai);
+        let (tx, rx) = oneshot::channel();
+
+        let job = RequestContext {
+            body,
+            deadline,
+            channel: tx,
+        };
+
+        if !bounded {
+            queue.scheduler.push(queue_key, job);
+        } else if !queue.scheduler.try_push(queue_key, job) {
+            return Err(queue.full());
+        }
+
+        crate::metrics::increment(&crate::metrics::SYNTHESES);
+
+        rx.await.unwrap().map_err(|e| {
+            if e.is::<Expired>() {
+                crate::metrics::increment(&crate::metrics::EXPIRED);
+                Rejection::new(StatusCode::GONE, e)
+            } else if e.is::<TooLong>() {
+                Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, e)
+            } else {
+                Rejection::new(StatusCode::BAD_REQUEST, e)
+            }
+        })
+    }
+
+    /// Rejects a batch of requests when the queue of any of them is full.
+    /// Admitted batches are queued whole by [`Workers::synthesize_all`].
+    pub fn admit(&self, api_reqs: &[ApiRequest]) -> Result<(), Rejection> {
+        for api_req in api_reqs {
+            let queue = self.queue(validate(api_req)?);
+            let queue_key = api_req.queue_key.as_deref().unwrap_or_default();
+
+            if queue.scheduler.is_full(queue_key) {
+                return Err(queue.full());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Synthesizes an admitted batch concurrently, keeping its order. The
+    /// queue length limit does not apply, so a batch longer than the queue
+    /// still goes through; callers bound the batch size. Fails with the index
+    /// of the first failing request. The remaining requests are cancelled
+    /// then, or when the returned future is dropped.
+    pub async fn synthesize_all(
+        &self,
+        api_reqs: Vec<ApiRequest>,
+    ) -> Result<Vec<Arc<Vec<u8>>>, (usize, Rejection)> {
+        let mut handles = AbortOnDrop(
+            api_reqs
+                .into_iter()
+                .map(|api_req| {
+                    let workers = self.clone();
+                    tokio::spawn(async move { workers.synthesize_job(api_req, false).await })
+                })
+                .collect(),
+        );
+
+        let mut voices = vec![];
+
+        for (index, handle) in handles.0.iter_mut().enumerate() {
+            match handle.await.unwrap() {
+                Ok((voice, _)) => voices.push(voice),
+                Err(rejection) => return Err((index, rejection)),
+            }
+        }
+
+        Ok(voices)
+    }
+
+    /// Synthesizes a request through the result cache, sharing the work with
+    /// identical requests in flight.
+    pub async fn synthesize(
+        &self,
+        api_req: ApiRequest,
+    ) -> Result<(Arc<Vec<u8>>, Source), Rejection> {
+        self.synthesize_job(api_req, true).await
+    }
+
+    async fn synthesize_job(
+        &self,
+        mut api_req: ApiRequest,
+        bounded: bool,
+    ) -> Result<(Arc<Vec<u8>>, Source), Rejection> {
+        parse_text(&mut api_req.body)?;
+        let is_kansai = validate(&api_req)?;
+        let deadline = api_req.deadline();
+        let queue_key = api_req.queue_key.clone().unwrap_or_default();
+
+        if api_req.adaptive_speed {
+            let pending = self.queue(is_kansai).scheduler.len_of(&queue_key);
+            let factor = crate::adaptive::apply(&mut api_req.body, pending);
+
+            if factor > 1.0 {
+                tracing::info!("Queue key {queue_key:?} has {pending} pending, speed x{factor:.2}");
+            }
+        }
+
+        let key = crate::cache::key(&api_req.body, is_kansai);
+
+        let cached = tokio::task::spawn_blocking({
+            let key = key.clone();
+            move || crate::cache::get(&key)
+        })
+        .await
+        .unwrap();
+
+        if let Some(voice) = cached {
+            crate::metrics::increment(&crate::metrics::CACHE_HITS);
+            return Ok((voice, Source::Hit));
+        }
+
+        // Identical requests already being synthesized share that result. The
+        // deadline decides whether the job runs at all, so a request with one
+        // only shares with requests expiring at the same instant
+        let flight_key = match deadline {
+            Some(deadline) => format!("{key}@{deadline:?}"),
+            None => key.clone(),
+        };
+
+        let leader = loop {
+            match crate::coalesce::join(&flight_key) {
+                Flight::Leader(leader) => break leader,
+                Flight::Follower(rx) => {
+                    let Ok(outcome) = rx.await else {
+                        // The leader was cancelled, take over
+                        continue;
+                    };
+
+                    crate::metrics::increment(&crate::metrics::COALESCED);
+
+                    return outcome.map(|voice| (voice, Source::Coalesced));
+                }
+            }
+        };
+
+        match self
+            .try_dispatch(api_req.body, is_kansai, deadline, &queue_key, bounded)
+            .await
+        {
+            Ok(voice) => {
+                let voice = Arc::new(voice);
+
+                tokio::task::spawn_blocking({
+                    let voice = voice.clone();
+                    move || crate::cache::put(&key, voice)
+                });
+
+                leader.finish(Ok(voice.clone()));
+                Ok((voice, Source::Miss))
+            }
+            Err(e) => {
+                leader.finish(Err(e.clone()));
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Splits long texts into chunks synthesized one after another.
+
+/// Characters ending a sentence; the chunk keeps them.
+fn is_sentence_end(c: char) -> bool {
+    matches!(c, '。' | '！' | '？' | '!' | '?' | '\n')
+}
+
+/// Characters a sentence too long for one chunk is preferably cut after.
+fn is_clause_end(c: char) -> bool {
+    matches!(c, '、' | '，' | ',' | ' ' | '　')
+}
+
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        if is_sentence_end(c) {
+            let end = i + c.len_utf8();
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+
+    sentences.push(&text[start..]);
+    sentences
+}
+
+/// Cuts a sentence longer than `max_chars` at clause boundaries, or anywhere
+/// if a clause is still too long.
+fn cut(sentence: &str, max_chars: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut piece = String::new();
+    let mut piece_chars = 0;
+    let mut last_clause_end = None;
+
+    for c in sentence.chars() {
+        piece.push(c);
+        piece_chars += 1;
+
+        if is_clause_end(c) {
+            last_clause_end = Some(piece.len());
+        }
+
+        if piece_chars >= max_chars {
+            let at = last_clause_end.take().unwrap_or(piece.len());
+            let rest = piece.split_off(at);
+            pieces.push(std::mem::replace(&mut piece, rest));
+            piece_chars = piece.chars().count();
+        }
+    }
+
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+
+    pieces
+}
+
+/// Splits `text` at sentence boundaries into chunks of at most `max_chars`
+/// characters, packing consecutive sentences together. Chunks with nothing
+/// but whitespace are dropped.
+pub fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+
+    for sentence in sentences(text) {
+        // Closing punctuation does not count, so it is never cut off alone
+        let body = sentence.trim_end_matches(|c: char| is_sentence_end(c) || c.is_whitespace());
+        let sentence_chars = body.chars().count();
+
+        if chunk_chars + sentence_chars > max_chars && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_chars = 0;
+        }
+
+        if sentence_chars > max_chars {
+            let mut pieces = cut(body, max_chars);
+            pieces.last_mut().unwrap().push_str(&sentence[body.len()..]);
+            chunks.extend(pieces);
+        } else {
+            chunk.push_str(sentence);
+            chunk_chars += sentence_chars;
+        }
+    }
+
+    chunks.push(chunk);
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
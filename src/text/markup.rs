@@ -0,0 +1,210 @@
+//! Inline markup for chat: `[speed=1.5]...[/]`, `[pitch=1.2]...[/]`,
+//! `[volume=0.8]...[/]`, `[voice=akari_44]...[/]` and `[pause=500]`.
+//!
+//! Settings of one tag are separated by spaces (`[speed=1.5 pitch=1.2]`) and
+//! `[/]` closes the latest open tag. `\[`, `\]` and `\\` are literal.
+
+use super::{MAX_BREAK_MS, Prosody, Segment, push_speech};
+
+struct Span {
+    prosody: Prosody,
+    tag: String,
+}
+
+/// A factor of `key`; only the volume may be zero.
+fn factor(key: &str, value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|f| f.is_finite() && (*f > 0.0 || key == "volume" && *f == 0.0))
+        .ok_or_else(|| format!("Invalid {key} \"{value}\""))
+}
+
+/// Applies the settings of an opening tag to `prosody`.
+fn open(tag: &str, prosody: &mut Prosody) -> Result<(), String> {
+    for setting in tag.split_whitespace() {
+        let Some((key, value)) = setting.split_once('=') else {
+            return Err(format!("Expected key=value in [{tag}]"));
+        };
+
+        match key {
+            "speed" => prosody.rate *= factor(key, value)?,
+            "pitch" => prosody.pitch *= factor(key, value)?,
+            "volume" => prosody.volume *= factor(key, value)?,
+            "voice" if !value.is_empty() => prosody.voice_id = Some(value.to_string()),
+            "pause" => return Err(format!("pause cannot be combined in [{tag}]")),
+            _ => return Err(format!("Unknown setting \"{key}\" in [{tag}]")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses marked-up text into segments. Errors give the character position
+/// of the malformed markup.
+pub fn parse(text: &str) -> Result<Vec<Segment>, String> {
+    let chars: Vec<char> = text.chars().collect();
+
+    let mut segments = vec![];
+    let mut spans: Vec<Span> = vec![];
+    let mut plain = String::new();
+    let mut i = 0;
+
+    let prosody = |spans: &[Span]| spans.last().map(|s| s.prosody.clone()).unwrap_or_default();
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if matches!(chars.get(i + 1), Some('[' | ']' | '\\')) => {
+                plain.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            '[' => (),
+            c => {
+                plain.push(c);
+                i += 1;
+                continue;
+            }
+        }
+
+        let Some(length) = chars[i + 1..].iter().position(|c| *c == ']') else {
+            return Err(format!("Unclosed [ at {i}, write \\[ for a literal one"));
+        };
+
+        let tag: String = chars[i + 1..i + 1 + length].iter().collect();
+        let tag = tag.trim();
+
+        push_speech(&mut segments, &plain, &prosody(&spans));
+        plain.clear();
+
+        if tag == "/" {
+            if spans.pop().is_none() {
+                return Err(format!("[/] at {i} closes nothing"));
+            }
+        } else if let Some(ms) = tag.strip_prefix("pause=") {
+            let ms = ms
+                .parse()
+                .ok()
+                .filter(|ms| *ms <= MAX_BREAK_MS)
+                .ok_or_else(|| {
+                    format!("Invalid pause \"{ms}\" at {i}, at most {MAX_BREAK_MS} ms")
+                })?;
+
+            segments.push(Segment::Break { ms });
+        } else if tag.is_empty() {
+            return Err(format!("Empty [] at {i}"));
+        } else {
+            let mut prosody = prosody(&spans);
+            open(tag, &mut prosody).map_err(|e| format!("{e} at {i}"))?;
+
+            spans.push(Span {
+                prosody,
+                tag: tag.to_string(),
+            });
+        }
+
+        i += length + 2;
+    }
+
+    if let Some(span) = spans.last() {
+        return Err(format!("[{}] is not closed with [/]", span.tag));
+    }
+
+    push_speech(&mut segments, &plain, &Prosody::default());
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speech(text: &str, prosody: Prosody) -> Segment {
+        Segment::Speech {
+            text: text.to_string(),
+            prosody,
+        }
+    }
+
+    #[test]
+    fn malformed_markup_is_rejected() {
+        for text in [
+            "[speed=1.5 こんにちは",
+            "こんにちは[/]",
+            "[speed=1.5]こんにちは",
+            "[]こんにちは",
+            "[speed]こんにちは[/]",
+            "[loud=2]こんにちは[/]",
+            "[speed=1.5 pause=500]こんにちは[/]",
+        ] {
+            assert!(parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn pauses_become_silence() {
+        let segments = parse("こんにちは[pause=500]元気？").unwrap();
+
+        assert_eq!(
+            segments,
+            [
+                speech("こんにちは", Prosody::default()),
+                Segment::Break { ms: 500 },
+                speech("元気？", Prosody::default()),
+            ]
+        );
+
+        assert!(parse("[pause=10001]").is_err());
+        assert!(parse("[pause=4000000000]").is_err());
+        assert!(parse("[pause=-1]").is_err());
+    }
+
+    #[test]
+    fn tags_nest_and_escapes_are_literal() {
+        let segments = parse("[speed=2][pitch=1.5]高い[/]速い[/]\\[普通\\]").unwrap();
+
+        let fast = Prosody {
+            rate: 2.0,
+            ..Prosody::default()
+        };
+
+        let high = Prosody {
+            pitch: 1.5,
+            ..fast.clone()
+        };
+
+        assert_eq!(
+            segments,
+            [
+                speech("高い", high),
+                speech("速い", fast),
+                speech("[普通]", Prosody::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_speed_and_pitch_are_rejected() {
+        for text in [
+            "[speed=0]a[/]",
+            "[pitch=0]a[/]",
+            "[speed=-1]a[/]",
+            "[pitch=NaN]a[/]",
+        ] {
+            assert!(parse(text).is_err(), "{text}");
+        }
+
+        let silent = parse("[volume=0]a[/]").unwrap();
+
+        assert_eq!(
+            silent,
+            [speech(
+                "a",
+                Prosody {
+                    volume: 0.0,
+                    ..Prosody::default()
+                }
+            )]
+        );
+    }
+}
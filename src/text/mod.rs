@@ -0,0 +1,103 @@
+pub mod chunk;
+pub mod markup;
+pub mod novel;
+pub mod ruby;
+pub mod srt;
+pub mod ssml;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest single break, as in SSML.
+pub const MAX_BREAK_MS: u32 = 10_000;
+
+/// Longest silence all the breaks of a text add up to.
+const MAX_TOTAL_BREAK_MS: u64 = 60_000;
+
+/// How the `text` of a request is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    #[default]
+    Plain,
+    Ssml,
+
+    /// Inline `[speed=1.5]...[/]` markup, see [`markup`].
+    Markup,
+}
+
+/// Changes to the voice and prosody of the request for part of its text.
+/// The factors multiply the request values.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Prosody {
+    pub voice_id: Option<String>,
+    pub rate: f32,
+    pub pitch: f32,
+    pub volume: f32,
+}
+
+impl Default for Prosody {
+    fn default() -> Self {
+        Self {
+            voice_id: None,
+            rate: 1.0,
+            pitch: 1.0,
+            volume: 1.0,
+        }
+    }
+}
+
+/// A part of a marked-up text, synthesized one after another.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Segment {
+    Speech { text: String, prosody: Prosody },
+    Break { ms: u32 },
+}
+
+/// Appends speech to `segments`, merging it into the last segment when the
+/// prosody is the same.
+fn push_speech(segments: &mut Vec<Segment>, text: &str, prosody: &Prosody) {
+    if text.is_empty() {
+        return;
+    }
+
+    if let Some(Segment::Speech {
+        text: last,
+        prosody: last_prosody,
+    }) = segments.last_mut()
+        && last_prosody == prosody
+    {
+        last.push_str(text);
+        return;
+    }
+
+    segments.push(Segment::Speech {
+        text: text.to_string(),
+        prosody: prosody.clone(),
+    });
+}
+
+/// Splits `text` into segments according to `format`, or `None` for plain
+/// text. Errors describe what is wrong with the markup.
+pub fn parse(text: &str, format: TextFormat) -> Result<Option<Vec<Segment>>, String> {
+    let segments = match format {
+        TextFormat::Plain => return Ok(None),
+        TextFormat::Ssml => ssml::parse(text)?,
+        TextFormat::Markup => markup::parse(text)?,
+    };
+
+    let total: u64 = segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Break { ms } => *ms as u64,
+            Segment::Speech { .. } => 0,
+        })
+        .sum();
+
+    if total > MAX_TOTAL_BREAK_MS {
+        return Err(format!(
+            "Breaks add up to {total} ms, at most {MAX_TOTAL_BREAK_MS} ms are allowed"
+        ));
+    }
+
+    Ok(Some(segments))
+}
@@ -0,0 +1,76 @@
+//! Splits novel text into narration and quoted dialogue.
+
+use std::collections::HashMap;
+
+fn push(parts: &mut Vec<(String, String)>, voice_id: &str, text: &str) {
+    let text = text.trim();
+
+    if !text.is_empty() {
+        parts.push((voice_id.to_string(), text.to_string()));
+    }
+}
+
+/// Splits `text` into `(voice ID, text)` parts: text in 「」 or 『』 is
+/// spoken by `dialogue`, the rest by `narrator`. A quote directly preceded
+/// by a name of `speakers` at the start of its line (`名前「...」`, or
+/// `名前：「...」`) is spoken by that voice instead, and the name is not read.
+pub fn split(
+    text: &str,
+    narrator: &str,
+    dialogue: &str,
+    speakers: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut parts = vec![];
+    let mut narration = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        let close = match c {
+            '「' => '」',
+            '『' => '』',
+            _ => {
+                narration.push(c);
+                continue;
+            }
+        };
+
+        // Up to the matching bracket, nested quotes included
+        let mut quote = String::new();
+        let mut depth = 1;
+
+        for q in chars.by_ref() {
+            if q == close {
+                depth -= 1;
+
+                if depth == 0 {
+                    break;
+                }
+            } else if q == c {
+                depth += 1;
+            }
+
+            quote.push(q);
+        }
+
+        let line_start = narration.rfind('\n').map_or(0, |i| i + 1);
+        let name = narration[line_start..]
+            .trim()
+            .trim_end_matches(['：', ':'])
+            .trim_end();
+
+        let voice_id = match speakers.get(name) {
+            Some(voice_id) => {
+                narration.truncate(line_start);
+                voice_id
+            }
+            None => dialogue,
+        };
+
+        push(&mut parts, narrator, &narration);
+        narration.clear();
+        push(&mut parts, voice_id, &quote);
+    }
+
+    push(&mut parts, narrator, &narration);
+    parts
+}
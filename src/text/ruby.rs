@@ -0,0 +1,131 @@
+//! Reading hints: Aozora Bunko `｜漢字《かんじ》` (the `｜` may be left out
+//! when the base is all kanji) and HTML `<ruby>漢字<rt>かんじ</rt></ruby>`,
+//! converted to the JEITA `<RUBY>` tags understood by the engine.
+
+fn is_kanji(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '々'
+            | '〆'
+            | 'ヶ'
+    )
+}
+
+fn jeita(base: &str, reading: &str) -> String {
+    let reading: String = reading
+        .chars()
+        .filter(|c| !matches!(c, '"' | '<' | '>'))
+        .collect();
+
+    format!("<RUBY ruby=\"{reading}\">{base}</RUBY>")
+}
+
+/// Removes every `<tag>...</tag>` from `html`.
+fn strip_element(html: &str, tag: &str) -> String {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut stripped = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find(&open) {
+        stripped.push_str(&rest[..start]);
+
+        match rest[start..].find(&close) {
+            Some(end) => rest = &rest[start + end + close.len()..],
+            None => rest = "",
+        }
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+/// `<ruby>` elements, possibly with several base/`<rt>` pairs and `<rp>`
+/// fallback parentheses.
+fn convert_html(text: &str) -> String {
+    let mut converted = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("<ruby>") {
+        let Some(end) = rest[start..].find("</ruby>") else {
+            break;
+        };
+
+        converted.push_str(&rest[..start]);
+
+        let inner = strip_element(&rest[start + "<ruby>".len()..start + end], "rp")
+            .replace("<rb>", "")
+            .replace("</rb>", "");
+
+        let mut inner = inner.as_str();
+
+        while let Some(rt) = inner.find("<rt>") {
+            let base = &inner[..rt];
+            let after = &inner[rt + "<rt>".len()..];
+            let (reading, next) = after.split_once("</rt>").unwrap_or((after, ""));
+
+            converted.push_str(&jeita(base, reading));
+            inner = next;
+        }
+
+        converted.push_str(inner);
+        rest = &rest[start + end + "</ruby>".len()..];
+    }
+
+    converted.push_str(rest);
+    converted
+}
+
+fn convert_aozora(text: &str) -> String {
+    let mut converted = String::new();
+
+    // Where the base marked by '｜' starts in `converted`
+    let mut base_start = None;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+
+        if c == '｜' {
+            base_start = Some(converted.len());
+            continue;
+        }
+
+        let Some((reading, after)) = rest.split_once('》').filter(|_| c == '《') else {
+            converted.push(c);
+            continue;
+        };
+
+        let start = base_start.take().unwrap_or_else(|| {
+            converted
+                .char_indices()
+                .rev()
+                .take_while(|(_, c)| is_kanji(*c))
+                .last()
+                .map_or(converted.len(), |(i, _)| i)
+        });
+
+        if start == converted.len() || reading.is_empty() {
+            converted.push(c);
+            continue;
+        }
+
+        let base = converted.split_off(start);
+        converted.push_str(&jeita(&base, reading));
+        rest = after;
+    }
+
+    converted
+}
+
+/// Converts the ruby notations in `text`, or `None` when there is none.
+pub fn to_jeita(text: &str) -> Option<String> {
+    if !text.contains('《') && !text.contains("<ruby>") {
+        return None;
+    }
+
+    let converted = convert_aozora(&convert_html(text));
+    (converted != text).then_some(converted)
+}
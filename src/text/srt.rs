@@ -0,0 +1,111 @@
+//! SubRip (`.srt`) subtitles.
+
+pub struct Subtitle {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub text: String,
+}
+
+/// `00:01:02,345` (or with a '.' before the milliseconds).
+fn timestamp(s: &str) -> Option<u64> {
+    let (hms, ms) = s.trim().split_once([',', '.'])?;
+    let mut parts = hms.split(':').map(|p| p.parse::<u64>().ok());
+
+    let (h, m, s) = (parts.next()??, parts.next()??, parts.next()??);
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    // A fraction of a second, `5` being 500 ms
+    if ms.is_empty() || ms.len() > 3 || !ms.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let ms = format!("{ms:0<3}").parse::<u64>().ok()?;
+
+    Some(((h * 60 + m) * 60 + s) * 1000 + ms)
+}
+
+/// Removes `<i>`-style tags and `{\an8}`-style overrides.
+fn strip_tags(line: &str) -> String {
+    let mut stripped = String::new();
+    let mut closing = None;
+
+    for c in line.chars() {
+        match (closing, c) {
+            (Some(close), c) if c == close => closing = None,
+            (Some(_), _) => (),
+            (None, '<') => closing = Some('>'),
+            (None, '{') => closing = Some('}'),
+            (None, c) => stripped.push(c),
+        }
+    }
+
+    stripped
+}
+
+/// Parses the subtitles, ordered by start time. Subtitles without text are
+/// dropped.
+pub fn parse(srt: &str) -> Result<Vec<Subtitle>, String> {
+    let srt = srt.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let mut subtitles = vec![];
+    let mut lines = srt.lines().peekable();
+    let mut block = 0;
+
+    while lines.peek().is_some() {
+        let lines: Vec<_> = lines
+            .by_ref()
+            .skip_while(|line| line.trim().is_empty())
+            .take_while(|line| !line.trim().is_empty())
+            .collect();
+
+        if lines.is_empty() {
+            break;
+        }
+
+        block += 1;
+
+        // The counter line is optional
+        let timing = lines
+            .iter()
+            .position(|line| line.contains("-->"))
+            .filter(|i| *i <= 1)
+            .ok_or_else(|| format!("Subtitle {block}: missing the timing line"))?;
+
+        let (start, end) = lines[timing].split_once("-->").unwrap();
+
+        // Coordinates may follow the end time
+        let end = end.split_whitespace().next().unwrap_or_default();
+
+        let (Some(start_ms), Some(end_ms)) = (timestamp(start), timestamp(end)) else {
+            return Err(format!(
+                "Subtitle {block}: invalid timing \"{}\"",
+                lines[timing]
+            ));
+        };
+
+        if end_ms < start_ms {
+            return Err(format!("Subtitle {block}: ends before it starts"));
+        }
+
+        let text = lines[timing + 1..]
+            .iter()
+            .map(|line| strip_tags(line).trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if !text.is_empty() {
+            subtitles.push(Subtitle {
+                start_ms,
+                end_ms,
+                text,
+            });
+        }
+    }
+
+    subtitles.sort_by_key(|subtitle| subtitle.start_ms);
+
+    Ok(subtitles)
+}
@@ -0,0 +1,546 @@
+//! The SSML subset accepted as `text`: `<speak>`, `<break>`, `<prosody>`,
+//! `<voice>`, `<say-as>` and `<sub>`.
+
+use std::collections::BTreeSet;
+
+use super::{MAX_BREAK_MS, Prosody, Segment, push_speech};
+
+enum Token<'a> {
+    Open {
+        name: &'a str,
+        attrs: Vec<(&'a str, String)>,
+        empty: bool,
+    },
+    Close(&'a str),
+    Text(String),
+}
+
+/// Text read literally instead of being spoken as written.
+enum Literal {
+    SayAs {
+        interpret_as: String,
+        format: Option<String>,
+        text: String,
+    },
+    Sub {
+        alias: String,
+    },
+}
+
+struct Frame<'a> {
+    name: &'a str,
+    prosody: Prosody,
+    literal: Option<Literal>,
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let end = rest
+            .find(';')
+            .ok_or_else(|| format!("Unterminated entity &{rest}"))?;
+
+        let entity = &rest[..end];
+
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("Unknown entity &{entity};"))?,
+        };
+
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+    }
+
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+fn parse_attrs<'a>(tag: &'a str, mut rest: &'a str) -> Result<Vec<(&'a str, String)>, String> {
+    let mut attrs = vec![];
+
+    loop {
+        rest = rest.trim_start();
+
+        if rest.is_empty() {
+            return Ok(attrs);
+        }
+
+        let malformed = || format!("Malformed attributes in <{tag}>");
+
+        let (key, value) = rest.split_once('=').ok_or_else(malformed)?;
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let quote = quote.ok_or_else(malformed)?;
+        let (value, after) = value[1..].split_once(quote).ok_or_else(malformed)?;
+
+        attrs.push((key.trim(), unescape(value)?));
+        rest = after;
+    }
+}
+
+fn tokenize(ssml: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = vec![];
+    let mut rest = ssml;
+
+    while let Some(start) = rest.find('<') {
+        if start > 0 {
+            tokens.push(Token::Text(unescape(&rest[..start])?));
+        }
+
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let end = comment.find("-->").ok_or("Unterminated comment")?;
+            rest = &comment[end + 3..];
+            continue;
+        }
+
+        if let Some(declaration) = rest.strip_prefix("<?") {
+            let end = declaration.find("?>").ok_or("Unterminated declaration")?;
+            rest = &declaration[end + 2..];
+            continue;
+        }
+
+        // Attribute values may contain '>'
+        let mut quote = None;
+        let end = rest
+            .char_indices()
+            .find(|(_, c)| match quote {
+                Some(q) if *c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if *c == '"' || *c == '\'' => {
+                    quote = Some(*c);
+                    false
+                }
+                None => *c == '>',
+            })
+            .map(|(i, _)| i)
+            .ok_or("Unterminated tag")?;
+
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            tokens.push(Token::Close(name.trim()));
+            continue;
+        }
+
+        let (tag, empty) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+
+        let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+
+        if name.is_empty() {
+            return Err("Empty tag name".to_string());
+        }
+
+        tokens.push(Token::Open {
+            name,
+            attrs: parse_attrs(name, attrs)?,
+            empty,
+        });
+    }
+
+    if !rest.is_empty() {
+        tokens.push(Token::Text(unescape(rest)?));
+    }
+
+    Ok(tokens)
+}
+
+/// `<break time="500ms">` or `<break strength="strong">`.
+fn break_ms(attrs: &[(&str, String)]) -> Result<u32, String> {
+    let attr = |key| {
+        attrs
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    if let Some(time) = attr("time") {
+        let ms = match time.strip_suffix("ms") {
+            Some(ms) => ms.trim().parse::<f32>().ok(),
+            None => time
+                .strip_suffix('s')
+                .and_then(|s| s.trim().parse::<f32>().ok())
+                .map(|s| s * 1000.0),
+        };
+
+        let ms = ms
+            .filter(|ms| *ms >= 0.0)
+            .ok_or_else(|| format!("Invalid break time \"{time}\""))?;
+
+        if ms > MAX_BREAK_MS as f32 {
+            return Err(format!("Break time \"{time}\" exceeds {MAX_BREAK_MS} ms"));
+        }
+
+        return Ok(ms as u32);
+    }
+
+    match attr("strength").unwrap_or("medium") {
+        "none" => Ok(0),
+        "x-weak" => Ok(100),
+        "weak" => Ok(250),
+        "medium" => Ok(500),
+        "strong" => Ok(800),
+        "x-strong" => Ok(1200),
+        strength => Err(format!("Invalid break strength \"{strength}\"")),
+    }
+}
+
+/// A `<prosody>` attribute value as a factor: a keyword, a percentage
+/// (relative when signed), semitones for `pitch`, decibels for `volume` or
+/// a bare number for `rate`.
+fn prosody_factor(attr: &str, value: &str) -> Result<f32, String> {
+    let keyword = match (attr, value) {
+        (_, "default" | "medium") => Some(1.0),
+        ("rate", "x-slow") => Some(0.5),
+        ("rate", "slow") => Some(0.75),
+        ("rate", "fast") => Some(1.5),
+        ("rate", "x-fast") => Some(2.0),
+        ("pitch", "x-low") => Some(0.8),
+        ("pitch", "low") => Some(0.9),
+        ("pitch", "high") => Some(1.1),
+        ("pitch", "x-high") => Some(1.2),
+        ("volume", "silent") => Some(0.0),
+        ("volume", "x-soft") => Some(0.25),
+        ("volume", "soft") => Some(0.5),
+        ("volume", "loud") => Some(1.5),
+        ("volume", "x-loud") => Some(2.0),
+        _ => None,
+    };
+
+    let number = |s: &str| s.trim().parse::<f32>().ok();
+    let signed = value.starts_with(['+', '-']);
+
+    let factor = keyword.or_else(|| {
+        if let Some(percent) = value.strip_suffix('%') {
+            number(percent).map(|p| if signed { 1.0 + p / 100.0 } else { p / 100.0 })
+        } else if let Some(st) = value.strip_suffix("st").filter(|_| attr == "pitch") {
+            number(st).map(|st| 2f32.powf(st / 12.0))
+        } else if let Some(db) = value.strip_suffix("dB").filter(|_| attr == "volume") {
+            number(db).map(|db| 10f32.powf(db / 20.0))
+        } else if attr == "rate" {
+            number(value)
+        } else {
+            None
+        }
+    });
+
+    // Only the volume may be zero, the engine rejects a zero rate or pitch
+    factor
+        .filter(|f| f.is_finite() && (*f > 0.0 || attr == "volume" && *f == 0.0))
+        .ok_or_else(|| format!("Invalid prosody {attr} \"{value}\""))
+}
+
+/// `2024-10-18` read as a date in the order given by `format` (`ymd` by
+/// default).
+fn say_date(text: &str, format: Option<&str>) -> Result<String, String> {
+    let format = format.unwrap_or("ymd");
+    let invalid = || format!("Invalid date \"{text}\" for format \"{format}\"");
+
+    let parts: Vec<_> = text.trim().split(['-', '/', '.']).collect();
+
+    if parts.len() != format.len() {
+        return Err(invalid());
+    }
+
+    let mut date = [None; 3];
+
+    for (field, part) in format.chars().zip(parts) {
+        let value = part.parse::<u32>().map_err(|_| invalid())?;
+
+        match field {
+            'y' => date[0] = Some(value),
+            'm' => date[1] = Some(value),
+            'd' => date[2] = Some(value),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(date
+        .iter()
+        .zip(["年", "月", "日"])
+        .filter_map(|(value, unit)| value.map(|v| format!("{v}{unit}")))
+        .collect())
+}
+
+impl Literal {
+    fn text(self) -> Result<String, String> {
+        match self {
+            Literal::Sub { alias } => Ok(alias),
+            Literal::SayAs {
+                interpret_as,
+                format,
+                text,
+            } => match interpret_as.as_str() {
+                "characters" => Ok(text
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+                    .join(" ")),
+                "cardinal" => Ok(text
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && !matches!(c, ',' | '，'))
+                    .collect()),
+                "date" => say_date(&text, format.as_deref()),
+                // Listed as unsupported
+                _ => Ok(text),
+            },
+        }
+    }
+}
+
+/// Parses an SSML document into segments. Every unsupported element or
+/// attribute is listed in the error.
+pub fn parse(ssml: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    let mut stack: Vec<Frame> = vec![];
+    let mut unsupported = BTreeSet::new();
+    let mut seen_root = false;
+
+    for token in tokenize(ssml)? {
+        let prosody = stack.last().map(|f| f.prosody.clone()).unwrap_or_default();
+
+        match token {
+            Token::Text(text) => match stack.last_mut() {
+                Some(Frame {
+                    literal: Some(Literal::SayAs { text: literal, .. }),
+                    ..
+                }) => literal.push_str(&text),
+                Some(Frame {
+                    literal: Some(Literal::Sub { .. }),
+                    ..
+                }) => (),
+                Some(_) => push_speech(&mut segments, &text, &prosody),
+                None if text.trim().is_empty() => (),
+                None => return Err("Text outside <speak>".to_string()),
+            },
+
+            Token::Open { name, attrs, empty } => {
+                if let Some(frame) = stack.last()
+                    && frame.literal.is_some()
+                {
+                    return Err(format!("<{}> may only contain text", frame.name));
+                }
+
+                if stack.is_empty() && (name != "speak" || seen_root) {
+                    return Err("Expected a single <speak> root element".to_string());
+                }
+
+                let attr = |key| {
+                    attrs
+                        .iter()
+                        .find(|(k, _)| *k == key)
+                        .map(|(_, v)| v.clone())
+                };
+
+                let mut frame = Frame {
+                    name,
+                    prosody: prosody.clone(),
+                    literal: None,
+                };
+
+                match name {
+                    "speak" if stack.is_empty() => seen_root = true,
+                    "speak" => return Err("<speak> must be the root element".to_string()),
+                    "break" => segments.push(Segment::Break {
+                        ms: break_ms(&attrs)?,
+                    }),
+                    "prosody" => {
+                        for (key, value) in &attrs {
+                            let factor = match *key {
+                                "rate" | "pitch" | "volume" => prosody_factor(key, value)?,
+                                _ => {
+                                    unsupported.insert(format!("<prosody {key}>"));
+                                    continue;
+                                }
+                            };
+
+                            match *key {
+                                "rate" => frame.prosody.rate *= factor,
+                                "pitch" => frame.prosody.pitch *= factor,
+                                _ => frame.prosody.volume *= factor,
+                            }
+                        }
+                    }
+                    "voice" => {
+                        frame.prosody.voice_id =
+                            Some(attr("name").ok_or("<voice> requires a name")?);
+                    }
+                    "say-as" => {
+                        let interpret_as =
+                            attr("interpret-as").ok_or("<say-as> requires interpret-as")?;
+
+                        if !matches!(interpret_as.as_str(), "characters" | "cardinal" | "date") {
+                            unsupported.insert(format!("<say-as interpret-as=\"{interpret_as}\">"));
+                        }
+
+                        frame.literal = Some(Literal::SayAs {
+                            interpret_as,
+                            format: attr("format"),
+                            text: String::new(),
+                        });
+                    }
+                    "sub" => {
+                        frame.literal = Some(Literal::Sub {
+                            alias: attr("alias").ok_or("<sub> requires an alias")?,
+                        });
+                    }
+                    _ => {
+                        unsupported.insert(format!("<{name}>"));
+                    }
+                }
+
+                if !empty {
+                    stack.push(frame);
+                } else if let Some(literal) = frame.literal {
+                    push_speech(&mut segments, &literal.text()?, &prosody);
+                }
+            }
+
+            Token::Close(name) => {
+                let frame = stack.pop().ok_or_else(|| format!("Unexpected </{name}>"))?;
+
+                if frame.name != name {
+                    return Err(format!("Expected </{}>, found </{name}>", frame.name));
+                }
+
+                if let Some(literal) = frame.literal {
+                    push_speech(&mut segments, &literal.text()?, &prosody);
+                }
+            }
+        }
+    }
+
+    if let Some(frame) = stack.last() {
+        return Err(format!("Unclosed <{}>", frame.name));
+    }
+
+    if !seen_root {
+        return Err("Missing <speak> root element".to_string());
+    }
+
+    if !unsupported.is_empty() {
+        return Err(format!(
+            "Unsupported SSML: {}",
+            unsupported.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speech(text: &str, prosody: Prosody) -> Segment {
+        Segment::Speech {
+            text: text.to_string(),
+            prosody,
+        }
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        for ssml in [
+            "<speak>こんにちは",
+            "<speak><prosody rate=\"slow\">こんにちは</speak>",
+            "<speak>こんにちは</prosody></speak>",
+            "<speak><break time=\"500ms\"</speak>",
+            "<speak><break time=500ms/></speak>",
+            "<speak>&nbsp;</speak>",
+            "こんにちは",
+            "<speak>a</speak><speak>b</speak>",
+        ] {
+            assert!(parse(ssml).is_err(), "{ssml}");
+        }
+    }
+
+    #[test]
+    fn breaks_become_silence() {
+        let segments = parse(
+            "<speak>こんにちは<break time=\"1.5s\"/>元気？<break strength=\"strong\"/></speak>",
+        )
+        .unwrap();
+
+        assert_eq!(
+            segments,
+            [
+                speech("こんにちは", Prosody::default()),
+                Segment::Break { ms: 1500 },
+                speech("元気？", Prosody::default()),
+                Segment::Break { ms: 800 },
+            ]
+        );
+
+        assert!(parse("<speak><break time=\"10001ms\"/></speak>").is_err());
+        assert!(parse("<speak><break strength=\"loud\"/></speak>").is_err());
+    }
+
+    #[test]
+    fn prosody_scales_the_enclosed_text() {
+        let segments =
+            parse("<speak><prosody rate=\"150%\" pitch=\"+10%\">速い</prosody>普通</speak>")
+                .unwrap();
+
+        let fast = Prosody {
+            rate: 1.5,
+            pitch: 1.1,
+            ..Prosody::default()
+        };
+
+        assert_eq!(
+            segments,
+            [speech("速い", fast), speech("普通", Prosody::default())]
+        );
+    }
+
+    #[test]
+    fn zero_rate_and_pitch_are_rejected() {
+        for attrs in [
+            "rate=\"0\"",
+            "rate=\"0%\"",
+            "pitch=\"0%\"",
+            "pitch=\"-100%\"",
+        ] {
+            let ssml = format!("<speak><prosody {attrs}>こんにちは</prosody></speak>");
+            assert!(parse(&ssml).is_err(), "{ssml}");
+        }
+
+        let silent = parse("<speak><prosody volume=\"0%\">こんにちは</prosody></speak>").unwrap();
+
+        assert_eq!(
+            silent,
+            [speech(
+                "こんにちは",
+                Prosody {
+                    volume: 0.0,
+                    ..Prosody::default()
+                }
+            )]
+        );
+    }
+}
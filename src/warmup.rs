@@ -0,0 +1,116 @@
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::model::{ApiRequest, Request};
+use crate::synthesis::{Rejection, Workers};
+
+/// Speaks `text` once with every voice so that the first real request does
+/// not pay for loading the voice data. Skipped when `text` is empty.
+pub async fn warm_up(workers: &Workers, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+
+    let mut voices: Vec<_> = crate::voices::get()
+        .iter()
+        .map(|(id, (_, info))| (id.clone(), info.dialect == "Kansai"))
+        .collect();
+
+    voices.sort();
+
+    for (voice_id, is_kansai) in voices {
+        let t_start_at = Instant::now();
+
+        match workers
+            .dispatch(Request::new(&voice_id, text), is_kansai, "")
+            .await
+        {
+            Ok(_) => tracing::info!("Warmed up {voice_id} in {:?}", t_start_at.elapsed()),
+            Err(e) => tracing::warn!("Failed to warm up {voice_id}: {e}"),
+        }
+    }
+}
+
+/// Prepares a phrase as `/api/tts` prepares a request without an API key.
+/// Limits change the cache key, so phrases would not be hits otherwise.
+fn prepare(phrase: &mut ApiRequest) -> Result<(), Rejection> {
+    crate::synthesis::prepare(&mut phrase.body, crate::limits::get(None))
+}
+
+/// Synthesizes every request of a JSON array (same shape as `/api/tts`) into
+/// the result cache.
+pub async fn fill_phrase_bank(workers: &Workers, path: &Path) -> Result<()> {
+    let phrases: Vec<ApiRequest> =
+        serde_json::from_slice(&std::fs::read(path).context("Failed to read phrase bank")?)
+            .context("Failed to parse phrase bank")?;
+
+    let t_start_at = Instant::now();
+    let total = phrases.len();
+    let mut cached = 0;
+
+    for mut phrase in phrases {
+        let text = phrase.body.text.clone();
+
+        if let Err(e) = prepare(&mut phrase) {
+            tracing::warn!("Failed to pre-synthesize {text:?}: {}", e.message);
+            continue;
+        }
+
+        match workers.synthesize(phrase).await {
+            Ok(_) => cached += 1,
+            Err(e) => tracing::warn!("Failed to pre-synthesize {text:?}: {}", e.message),
+        }
+    }
+
+    tracing::info!(
+        "Phrase bank: {cached}/{total} phrases cached in {:?}",
+        t_start_at.elapsed()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::{CharsPolicy, Limits, SecondsPolicy};
+    use crate::model::ApiRequest;
+
+    #[test]
+    fn banked_phrases_are_tts_cache_hits() {
+        crate::cache::init(1024 * 1024, None, "test".to_string(), 200).unwrap();
+        crate::limits::init(
+            Limits {
+                max_chars: Some(100),
+                chars_policy: CharsPolicy::Truncate,
+                truncate_suffix: "以下略".to_string(),
+                max_seconds: Some(30.0),
+                seconds_policy: SecondsPolicy::FadeOut,
+                fade_out_ms: 500,
+            },
+            None,
+        )
+        .unwrap();
+
+        let phrase: ApiRequest =
+            serde_json::from_str(r#"{ "voice_id": "akari_44", "text": "いらっしゃい" }"#).unwrap();
+
+        // What /api/tts does with a request without an API key
+        let mut tts = phrase.clone();
+        crate::synthesis::prepare(&mut tts.body, crate::limits::get(None)).unwrap();
+
+        let mut banked = phrase.clone();
+        super::prepare(&mut banked).unwrap();
+
+        assert!(banked.body.output_limit.is_some());
+        assert_eq!(
+            crate::cache::key(&banked.body, false),
+            crate::cache::key(&tts.body, false)
+        );
+        assert_ne!(
+            crate::cache::key(&phrase.body, false),
+            crate::cache::key(&tts.body, false)
+        );
+    }
+}
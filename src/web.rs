@@ -1,20 +1,88 @@
 use axum::{
     Router,
-    extract::{Json, State},
-    http::{StatusCode, header},
-    response::IntoResponse,
-    routing::{get, post},
+    body::Bytes,
+    extract::{DefaultBodyLimit, Json, Path, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
 };
 use base64::prelude::*;
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, oneshot};
 
-use crate::model::{ApiRequest, RequestContext, Voice};
+use crate::audio::{SAMPLE_RATE, watermark, wav::Wav};
+use crate::model::{
+    ApiRequest, Asset, AudiobookParams, AudiobookStatus, DialogueRequest, DialogueTiming,
+    DubReport, DubRequest, Metrics, QueueStatus, Voice, WatermarkDetection,
+};
+use crate::synthesis::{Rejection, Workers};
+
+const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
 
 #[derive(Clone)]
 struct AppState {
-    worker_socket: mpsc::Sender<RequestContext>,
-    worker_socket_kansai: mpsc::Sender<RequestContext>,
+    workers: Workers,
+    admin_token: Option<String>,
+}
+
+fn plain_error(status: StatusCode, e: impl ToString) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "text/plain")],
+        e.to_string(),
+    )
+        .into_response()
+}
+
+fn wav_response(voice: &[u8], cache: &'static str) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "audio/wav"), (X_CACHE, cache)],
+        voice.to_vec(),
+    )
+        .into_response()
+}
+
+fn rejection_response(rejection: Rejection) -> Response {
+    tracing::warn!("{}", rejection.message);
+
+    let mut response = plain_error(rejection.status, rejection.message);
+
+    if let Some(retry_after) = rejection.retry_after {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+    }
+
+    response
+}
+
+/// The `X-Api-Key` header, which selects per-key limits.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers.get(X_API_KEY).and_then(|v| v.to_str().ok())
+}
+
+/// Checks the `Authorization: Bearer` header against `--admin-token`.
+fn authorize_admin(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = &state.admin_token else {
+        return Err((StatusCode::FORBIDDEN, "Admin API is disabled"));
+    };
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    if bearer != Some(token.as_str()) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid admin token"));
+    }
+
+    Ok(())
 }
 
 async fn root_handler() -> impl IntoResponse {
@@ -42,63 +110,322 @@ async fn voices_handler() -> Json<Vec<Voice>> {
 
 async fn tts_handler(
     State(state): State<AppState>,
-    Json(api_req): Json<ApiRequest>,
-) -> impl IntoResponse {
-    let voice_id = &api_req.body.voice_id;
-
-    let Some(info) = crate::voices::get().get(voice_id) else {
-        let e = format!("{voice_id} is not loaded");
-        tracing::warn!("{e}");
-        return (
-            StatusCode::BAD_REQUEST,
-            [(header::CONTENT_TYPE, "text/plain")],
-            e.to_string().into_bytes(),
-        );
-    };
+    headers: HeaderMap,
+    Json(mut api_req): Json<ApiRequest>,
+) -> Response {
+    crate::metrics::increment(&crate::metrics::TTS_REQUESTS);
 
-    let is_kansai = info.1.dialect == "Kansai";
+    let limits = crate::limits::get(api_key(&headers));
 
-    let worker = if api_req.is_kansai.unwrap_or(is_kansai) {
-        state.worker_socket_kansai
-    } else {
-        state.worker_socket
+    if let Err(rejection) = crate::synthesis::prepare(&mut api_req.body, limits) {
+        return rejection_response(rejection);
+    }
+
+    match state.workers.synthesize(api_req).await {
+        Ok((voice, source)) => wav_response(&voice, source.as_str()),
+        Err(rejection) => rejection_response(rejection),
+    }
+}
+
+async fn dialogue_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<DialogueRequest>,
+) -> Response {
+    let timing = request.timing;
+    let stems = request.stems;
+    let limits = crate::limits::get(api_key(&headers));
+
+    let dialogue = match crate::dialogue::synthesize(&state.workers, request, limits).await {
+        Ok(dialogue) => dialogue,
+        Err(rejection) => return rejection_response(rejection),
     };
 
-    let (tx, rx) = oneshot::channel();
+    if stems {
+        return match tokio::task::spawn_blocking(move || dialogue.to_zip())
+            .await
+            .unwrap()
+        {
+            Ok(zip) => (
+                [
+                    (header::CONTENT_TYPE, "application/zip"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"dialogue.zip\"",
+                    ),
+                ],
+                zip,
+            )
+                .into_response(),
+            Err(e) => plain_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")),
+        };
+    }
 
-    worker
-        .send(RequestContext {
-            body: api_req.body,
-            channel: tx,
+    let wav = dialogue.wav.encode();
+
+    if timing {
+        return Json(DialogueTiming {
+            audio: BASE64_STANDARD.encode(wav),
+            lines: dialogue.lines,
         })
+        .into_response();
+    }
+
+    ([(header::CONTENT_TYPE, "audio/wav")], wav).into_response()
+}
+
+async fn dub_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<DubRequest>,
+) -> Response {
+    let limits = crate::limits::get(api_key(&headers));
+
+    match crate::dub::synthesize(&state.workers, request, limits).await {
+        Ok(dub) => Json(DubReport {
+            audio: BASE64_STANDARD.encode(dub.wav.encode()),
+            adjusted: dub.adjusted,
+        })
+        .into_response(),
+        Err(rejection) => rejection_response(rejection),
+    }
+}
+
+async fn upload_audiobook_handler(
+    State(state): State<AppState>,
+    Query(params): Query<AudiobookParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let limits = crate::limits::get(api_key(&headers));
+
+    match crate::audiobook::start(&state.workers, body, params, limits).await {
+        Ok(status) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+        Err(rejection) => rejection_response(rejection),
+    }
+}
+
+async fn audiobooks_handler() -> Json<Vec<AudiobookStatus>> {
+    Json(crate::audiobook::list())
+}
+
+async fn audiobook_handler(Path(id): Path<String>) -> Response {
+    match crate::audiobook::status(&id) {
+        Some(status) => Json(status).into_response(),
+        None => plain_error(
+            StatusCode::NOT_FOUND,
+            format!("Audiobook {id} is not running"),
+        ),
+    }
+}
+
+async fn delete_audiobook_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err((status, e)) = authorize_admin(&state, &headers) {
+        return plain_error(status, e);
+    }
+
+    match tokio::task::spawn_blocking(move || crate::audiobook::remove(&id))
+        .await
+        .unwrap()
+    {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => plain_error(StatusCode::NOT_FOUND, "No such audiobook"),
+        Err(e) => {
+            tracing::warn!("{e:#}");
+            plain_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+        }
+    }
+}
+
+async fn audiobook_file_handler(Path((id, name)): Path<(String, String)>) -> Response {
+    let content_type = match name.rsplit_once('.') {
+        Some((_, "wav")) => "audio/wav",
+        Some((_, "json")) => "application/json",
+        _ => "application/octet-stream",
+    };
+
+    match tokio::task::spawn_blocking(move || crate::audiobook::file(&id, &name))
+        .await
+        .unwrap()
+    {
+        Some(bytes) => ([(header::CONTENT_TYPE, content_type)], bytes).into_response(),
+        None => plain_error(StatusCode::NOT_FOUND, "No such file"),
+    }
+}
+
+async fn metrics_handler() -> Json<Metrics> {
+    Json(crate::metrics::snapshot())
+}
+
+async fn queue_handler(State(state): State<AppState>) -> Json<QueueStatus> {
+    Json(QueueStatus {
+        workers: state.workers.status(),
+        metrics: crate::metrics::snapshot(),
+    })
+}
+
+async fn assets_handler() -> Json<Vec<Asset>> {
+    Json(
+        crate::assets::list()
+            .into_iter()
+            .map(|(name, duration)| Asset { name, duration })
+            .collect(),
+    )
+}
+
+async fn put_asset_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err((status, e)) = authorize_admin(&state, &headers) {
+        return plain_error(status, e);
+    }
+
+    match tokio::task::spawn_blocking(move || crate::assets::put(&name, &body))
+        .await
+        .unwrap()
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::warn!("{e:#}");
+            plain_error(StatusCode::BAD_REQUEST, format!("{e:#}"))
+        }
+    }
+}
+
+async fn delete_asset_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err((status, e)) = authorize_admin(&state, &headers) {
+        return plain_error(status, e);
+    }
+
+    match crate::assets::remove(&name) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => plain_error(StatusCode::NOT_FOUND, format!("{name} is not uploaded")),
+        Err(e) => {
+            tracing::warn!("{e:#}");
+            plain_error(StatusCode::BAD_REQUEST, format!("{e:#}"))
+        }
+    }
+}
+
+async fn purge_cache_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err((status, e)) = authorize_admin(&state, &headers) {
+        return plain_error(status, e);
+    }
+
+    let purged = tokio::task::spawn_blocking(crate::cache::purge)
         .await
         .unwrap();
 
-    match rx.await.unwrap() {
-        Ok(voice) => (StatusCode::OK, [(header::CONTENT_TYPE, "audio/wav")], voice),
+    tracing::info!("{purged} cached results purged");
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn reload_dictionaries_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err((status, e)) = authorize_admin(&state, &headers) {
+        return plain_error(status, e);
+    }
+
+    match tokio::task::spawn_blocking(crate::dictionary::reload)
+        .await
+        .unwrap()
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
         Err(e) => {
-            tracing::warn!("{e}");
-            (
-                StatusCode::BAD_REQUEST,
-                [(header::CONTENT_TYPE, "text/plain")],
-                e.to_string().into_bytes(),
-            )
+            tracing::warn!("{e:#}");
+            plain_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
         }
     }
 }
 
+async fn watermark_detect_handler(body: Bytes) -> Response {
+    let detection = tokio::task::spawn_blocking(move || {
+        let mut wav = Wav::decode(&body)?;
+
+        // Only resample what the detector reads
+        let frames = (watermark::DETECT_SAMPLES as u64 * wav.sample_rate as u64)
+            .div_ceil(SAMPLE_RATE as u64) as usize;
+        wav.samples.truncate(frames * wav.channels as usize);
+
+        anyhow::Ok(watermark::detect(&wav.to_mono()))
+    })
+    .await
+    .unwrap();
+
+    let detection = match detection {
+        Ok(detection) => detection,
+        Err(e) => {
+            tracing::warn!("{e}");
+            return plain_error(StatusCode::BAD_REQUEST, e);
+        }
+    };
+
+    Json(WatermarkDetection {
+        detected: detection.is_some(),
+        server_id: detection.as_ref().map(|d| d.server_id),
+        timestamp: detection.as_ref().map(|d| d.timestamp),
+        confidence: detection.as_ref().map(|d| d.confidence),
+    })
+    .into_response()
+}
+
 pub async fn serve(
     listener: TcpListener,
-    worker_socket: mpsc::Sender<RequestContext>,
-    worker_socket_kansai: mpsc::Sender<RequestContext>,
+    workers: Workers,
+    admin_token: Option<String>,
 ) -> Result<(), std::io::Error> {
     let app = Router::new()
         .route("/", get(root_handler))
         .route("/api/tts", post(tts_handler))
+        .route("/api/dialogue", post(dialogue_handler))
+        .route("/api/dub", post(dub_handler))
         .route("/api/voices", get(voices_handler))
+        .route(
+            "/api/audiobooks",
+            get(audiobooks_handler)
+                .post(upload_audiobook_handler)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route(
+            "/api/audiobooks/{id}",
+            get(audiobook_handler).delete(delete_audiobook_handler),
+        )
+        .route("/api/audiobooks/{id}/{file}", get(audiobook_file_handler))
+        .route("/api/metrics", get(metrics_handler))
+        .route("/api/queue", get(queue_handler))
+        .route("/api/assets", get(assets_handler))
+        .route(
+            "/api/admin/assets/{name}",
+            put(put_asset_handler)
+                .delete(delete_asset_handler)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/api/admin/cache", delete(purge_cache_handler))
+        .route(
+            "/api/admin/dictionaries/reload",
+            post(reload_dictionaries_handler),
+        )
+        .route(
+            "/api/watermark/detect",
+            post(watermark_detect_handler).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
         .with_state(AppState {
-            worker_socket,
-            worker_socket_kansai,
+            workers,
+            admin_token,
         });
 
     axum::serve(listener, app).await
@@ -1,18 +1,25 @@
+use std::collections::VecDeque;
 use std::ffi::{CStr, CString, c_char, c_void};
-use std::io::{Cursor, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use aitalked::{api::Aitalked, binding::*, model::*};
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result};
 use encoding_rs::SHIFT_JIS;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
-use crate::model::RequestContext;
+use crate::audio::{
+    self,
+    wav::{self, Cue, Info, Wav},
+};
+use crate::cache::Tier;
+use crate::model::Request;
+use crate::scheduler::Scheduler;
+use crate::text::{self, Segment};
 
-const WAV_HEADER_SIZE: usize = 44;
-
-fn path_to_sjis_cstring(path: &Path) -> CString {
+pub fn path_to_sjis_cstring(path: &Path) -> CString {
     CString::new(SHIFT_JIS.encode(path.to_str().unwrap()).0).unwrap()
 }
 
@@ -160,9 +167,6 @@ pub fn initialization(
     installation_dir: &Path,
     dll_name: &str,
     lang: &str,
-    word_dic: Option<&Path>,
-    phrase_dic: Option<&Path>,
-    symbol_dic: Option<&Path>,
     auth_seed: &str,
 ) -> Result<(Aitalked, BoxedTtsParam)> {
     let aitalked = unsafe { aitalked::load_dll(&installation_dir.join(dll_name)) }
@@ -188,29 +192,7 @@ pub fn initialization(
         anyhow::bail!("Failed to aitalked.init {code:?}");
     }
 
-    if let Some(word_dic) = word_dic {
-        let code = unsafe { aitalked.reload_word_dic(Some(&path_to_sjis_cstring(word_dic))) };
-
-        if code != ResultCode::SUCCESS {
-            anyhow::bail!("Failed to aitalked.reload_word_dic {code:?}");
-        }
-    }
-
-    if let Some(phrase_dic) = phrase_dic {
-        let code = unsafe { aitalked.reload_phrase_dic(Some(&path_to_sjis_cstring(phrase_dic))) };
-
-        if code != ResultCode::SUCCESS {
-            anyhow::bail!("Failed to aitalked.reload_phrase_dic {code:?}");
-        }
-    }
-
-    if let Some(symbol_dic) = symbol_dic {
-        let code = unsafe { aitalked.reload_symbol_dic(Some(&path_to_sjis_cstring(symbol_dic))) };
-
-        if code != ResultCode::SUCCESS {
-            anyhow::bail!("Failed to aitalked.reload_symbol_dic {code:?}");
-        }
-    }
+    crate::dictionary::load(&aitalked)?;
 
     let voice_name = find_voice_dbs(&dir_voice_dbs).unwrap();
 
@@ -272,20 +254,260 @@ fn to_nonempty_sjis_lossy(input: &str) -> Option<CString> {
     }
 }
 
-pub fn event_loop(
+/// Post-processes the engine output. `cues` are moved along with the audio
+/// they point at.
+fn post_process(body: &Request, samples: &mut Vec<i16>, cues: &mut [Cue]) {
+    let len = samples.len();
+    audio::stretch::apply(samples, body.post_speed, body.post_pitch);
+
+    if len > 0 {
+        let ratio = samples.len() as f64 / len as f64;
+        cues.iter_mut()
+            .for_each(|cue| cue.position = (cue.position as f64 * ratio) as u32);
+    }
+
+    let preset = body
+        .effect_preset
+        .as_deref()
+        .and_then(audio::effects::preset)
+        .unwrap_or_default();
+
+    audio::effects::apply(samples, preset.iter().chain(&body.effects));
+
+    if body.trim_silence {
+        let removed = audio::silence::trim(samples, body.silence_threshold) as u32;
+        cues.iter_mut()
+            .for_each(|cue| cue.position = cue.position.saturating_sub(removed));
+    }
+
+    let leading = audio::ms_to_samples(body.leading_padding_ms.unwrap_or(0));
+
+    audio::silence::pad(
+        samples,
+        leading,
+        audio::ms_to_samples(body.trailing_padding_ms.unwrap_or(0)),
+    );
+
+    cues.iter_mut()
+        .for_each(|cue| cue.position += leading as u32);
+
+    if let Some(background) = &body.background {
+        match crate::assets::get(&background.asset) {
+            Some(track) => audio::mix::mix_background(samples, &track, background),
+            None => tracing::warn!("Background asset {} is gone", background.asset),
+        }
+    }
+
+    audio::watermark::embed(samples);
+}
+
+fn to_wav(body: &Request, samples: Vec<i16>, cues: Vec<Cue>) -> Vec<u8> {
+    let mut wav = Wav::mono(samples);
+    wav.cues = cues;
+
+    if body.metadata {
+        wav.info = Info {
+            name: None,
+            artist: crate::voices::get()
+                .get(&body.voice_id)
+                .map(|(_, info)| info.name.clone()),
+            comment: Some(body.text.clone()),
+            software: Some(format!("aitalked-server {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: Some(wav::today()),
+        };
+    }
+
+    wav.encode()
+}
+
+/// Runs the text analysis stage and returns the AIKANA without the trailing '\0'.
+fn text_to_kana(
     aitalked: Aitalked,
-    mut boxed_tts_param: BoxedTtsParam,
-    mut rx: mpsc::Receiver<RequestContext>,
-) {
-    loop {
-        let ctx = rx.blocking_recv().unwrap();
+    boxed_tts_param: &mut BoxedTtsParam,
+    sjis_text: &CStr,
+) -> Result<Vec<u8>> {
+    boxed_tts_param.tts_param_mut().proc_text_buf = Some(text_buffer_callback);
+
+    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.set_param (text_to_kana) {code:?}");
+    }
+
+    let mut job_id = 0;
+
+    let mut kana = vec![];
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let mut context = ProcTextBufContext {
+        aitalked,
+        buffer: &mut kana,
+        notify: tx.clone(),
+        len_text_buf_bytes: boxed_tts_param.tts_param().len_text_buf_bytes,
+    };
+
+    let code = unsafe {
+        aitalked.text_to_kana(
+            &mut job_id,
+            &mut context as *mut ProcTextBufContext as *mut std::ffi::c_void,
+            sjis_text,
+        )
+    };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.text_to_kana {code:?}");
+    }
+
+    rx.blocking_recv().unwrap();
+
+    drop(context);
+
+    let code = unsafe { aitalked.close_kana(job_id, 0) };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.close_kana {code:?}");
+    }
+
+    // unload
+    boxed_tts_param.tts_param_mut().proc_text_buf = None;
+
+    Ok(kana)
+}
+
+/// Runs the speech synthesis stage and returns the raw PCM bytes.
+fn kana_to_speech(
+    aitalked: Aitalked,
+    boxed_tts_param: &mut BoxedTtsParam,
+    kana: &CStr,
+) -> Result<Vec<u8>> {
+    boxed_tts_param.tts_param_mut().proc_raw_buf = Some(raw_buf_callback);
+    boxed_tts_param.tts_param_mut().proc_event_tts = Some(tts_event_callback);
+    let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.set_param (kana_to_speech / set) {code:?}");
+    }
+
+    let mut job_id = 0;
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let mut buffer = vec![];
+
+    let mut context = TextToSpeechContext {
+        aitalked,
+        buffer: &mut buffer,
+        notify: tx.clone(),
+        len_raw_buf_words: boxed_tts_param.tts_param().len_raw_buf_words,
+    };
+
+    let code = unsafe {
+        aitalked.text_to_speech(
+            &mut job_id,
+            &mut context as *mut TextToSpeechContext as *mut std::ffi::c_void,
+            kana,
+        )
+    };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.text_to_speech {code:?}");
+    }
+
+    rx.blocking_recv().unwrap();
+
+    drop(context);
+
+    let code = unsafe { aitalked.close_speech(job_id, 0) };
+    if code != ResultCode::SUCCESS {
+        anyhow::bail!("Failed to aitalked.close_speech {code:?}");
+    }
+
+    Ok(buffer)
+}
+
+/// A job whose deadline passed while it was queued.
+#[derive(Debug)]
+pub struct Expired;
+
+impl std::fmt::Display for Expired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Deadline passed before synthesis started")
+    }
+}
+
+impl std::error::Error for Expired {}
 
+/// Number of finished jobs the synthesis time estimate is based on.
+const RECENT_JOBS: usize = 32;
+
+/// Shared between a worker thread and the web side.
+#[derive(Debug, Default)]
+pub struct Stats {
+    recent: Mutex<VecDeque<Duration>>,
+    completed: AtomicU64,
+}
+
+impl Stats {
+    fn record(&self, elapsed: Duration) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+
+        let mut recent = self.recent.lock().unwrap();
+
+        if recent.len() == RECENT_JOBS {
+            recent.pop_front();
+        }
+
+        recent.push_back(elapsed);
+    }
+
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Mean processing time of the recent jobs.
+    pub fn average(&self) -> Option<Duration> {
+        let recent = self.recent.lock().unwrap();
+
+        (!recent.is_empty()).then(|| recent.iter().sum::<Duration>() / recent.len() as u32)
+    }
+}
+
+struct Engine {
+    aitalked: Aitalked,
+    boxed_tts_param: BoxedTtsParam,
+
+    // AIKANA only depends on the text, the dialect of this worker and the
+    // dictionaries, so it is shared by every voice and prosody
+    kana_cache: Tier<Vec<u8>>,
+
+    /// PCM of the chunks of long texts, keyed by voice, prosody and text.
+    chunk_cache: Tier<Arc<Vec<i16>>>,
+    max_chunk_chars: usize,
+
+    dictionary_generation: u64,
+}
+
+impl Engine {
+    fn refresh_dictionaries(&mut self) {
+        if self.dictionary_generation == crate::dictionary::generation() {
+            return;
+        }
+
+        self.dictionary_generation = crate::dictionary::generation();
+        self.kana_cache.clear();
+        self.chunk_cache.clear();
+
+        match crate::dictionary::load(&self.aitalked) {
+            Ok(()) => tracing::info!("Dictionaries reloaded"),
+            Err(e) => tracing::warn!("{e}"),
+        }
+    }
+
+    /// Speaks `body.text` with the voice and prosody of `body`. Text without
+    /// anything to speak gives no samples.
+    fn synthesize(&mut self, body: &Request) -> Result<Vec<i16>> {
         let t_start_at = Instant::now();
+        let aitalked = self.aitalked;
+        let boxed_tts_param = &mut self.boxed_tts_param;
 
         /*\
         |*| Parameter Initialization
         \*/
-        let voice_name = &ctx.body.voice_id;
+        let voice_name = &body.voice_id;
         let voice_name_buff = voicename_to_buffer(voice_name);
 
         let Some(speaker) = boxed_tts_param
@@ -293,197 +515,254 @@ pub fn event_loop(
             .iter_mut()
             .find(|s| s.voice_name == voice_name_buff)
         else {
-            ctx.channel
-                .send(Err(anyhow!(
-                    "Failed to find speaker from tts_param {}",
-                    ctx.body.voice_id
-                )))
-                .unwrap();
-
-            continue;
+            anyhow::bail!("Failed to find speaker from tts_param {voice_name}");
         };
 
-        speaker.speed = ctx.body.speed;
-        speaker.pitch = ctx.body.pitch;
-        speaker.range = ctx.body.range;
-        speaker.pause_middle = ctx.body.pause_middle;
-        speaker.pause_long = ctx.body.pause_long;
-        speaker.pause_sentence = ctx.body.pause_sentence;
+        speaker.speed = body.speed;
+        speaker.pitch = body.pitch;
+        speaker.range = body.range;
+        speaker.pause_middle = body.pause_middle;
+        speaker.pause_long = body.pause_long;
+        speaker.pause_sentence = body.pause_sentence;
         boxed_tts_param.tts_param_mut().voice_name = speaker.voice_name;
-        boxed_tts_param.tts_param_mut().volume = ctx.body.volume;
+        boxed_tts_param.tts_param_mut().volume = body.volume;
         boxed_tts_param.tts_param_mut().proc_text_buf = None;
         boxed_tts_param.tts_param_mut().proc_raw_buf = None;
         boxed_tts_param.tts_param_mut().proc_event_tts = None;
 
+        // Readings are passed as JEITA ruby tags
+        let ruby = body
+            .ruby
+            .then(|| text::ruby::to_jeita(&body.text))
+            .flatten();
+
+        boxed_tts_param.tts_param_mut().extend_format = match ruby {
+            Some(_) => ExtendFormat::JEITA_RUBY,
+            None => ExtendFormat::NONE,
+        };
+
+        let text = ruby.as_ref().unwrap_or(&body.text);
+
         // Avoiding aitalked.text_to_kana INVALID_ARGUMENT
-        if let Some(sjis_text) = to_nonempty_sjis_lossy(&ctx.body.text) {
-            /*\
-            |*| Start Text2Kana
-            \*/
-            boxed_tts_param.tts_param_mut().proc_text_buf = Some(text_buffer_callback);
-
-            let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-            if code != ResultCode::SUCCESS {
-                ctx.channel
-                    .send(Err(anyhow!(
-                        "Failed to aitalked.set_param (text_to_kana) {code:?}"
-                    )))
-                    .unwrap();
-
-                continue;
+        let Some(sjis_text) = to_nonempty_sjis_lossy(text) else {
+            return Ok(vec![]);
+        };
+
+        /*\
+        |*| Start Text2Kana
+        \*/
+        // Ruby tags are plain text without JEITA_RUBY, so the format is part of the key
+        let key = serde_json::to_string(&(ruby.is_some(), text)).unwrap();
+        let cached = self.kana_cache.get(&key);
+        let kana_cached = cached.is_some();
+
+        let mut kana = match cached {
+            Some(kana) => kana,
+            None => {
+                let kana = text_to_kana(aitalked, boxed_tts_param, &sjis_text)?;
+                let size = (key.len() + kana.len()) as u64;
+                self.kana_cache.insert(key, kana.clone(), size);
+                kana
             }
+        };
 
-            let mut job_id = 0;
+        // Avoiding aitalked.text_to_speech INVALID_ARGUMENT
+        if kana.is_empty() {
+            return Ok(vec![]);
+        }
 
-            let mut kana = vec![];
-            let (tx, mut rx) = mpsc::channel(1);
+        // Add '\0'
+        kana.push(0);
 
-            let mut context = ProcTextBufContext {
-                aitalked,
-                buffer: &mut kana,
-                notify: tx.clone(),
-                len_text_buf_bytes: boxed_tts_param.tts_param().len_text_buf_bytes,
-            };
+        let kana = CStr::from_bytes_with_nul(&kana).unwrap();
 
-            let code = unsafe {
-                aitalked.text_to_kana(
-                    &mut job_id,
-                    &mut context as *mut ProcTextBufContext as *mut std::ffi::c_void,
-                    &sjis_text,
-                )
-            };
-            if code != ResultCode::SUCCESS {
-                ctx.channel
-                    .send(Err(anyhow!("Failed to aitalked.text_to_kana {code:?}")))
-                    .unwrap();
+        let t_kana_ready = Instant::now();
 
-                continue;
-            }
+        /*\
+        |*| Start Kana2Speech
+        \*/
+        let buffer = kana_to_speech(aitalked, boxed_tts_param, kana)?;
+
+        let t_speech_ready = Instant::now();
 
-            rx.blocking_recv().unwrap();
+        tracing::info!(
+            "Voice: {}, Kana: {:?}{}, Speech: {:?}",
+            voice_name,
+            t_kana_ready - t_start_at,
+            if kana_cached { " (cached)" } else { "" },
+            t_speech_ready - t_kana_ready,
+        );
 
-            drop(context);
+        Ok(audio::samples_from_bytes(&buffer))
+    }
+
+    /// Speaks the segments of `body` one after another, or its whole text
+    /// when it is plain.
+    fn speak(&mut self, body: &Request) -> Result<(Vec<i16>, Vec<Cue>)> {
+        let Some(segments) = &body.segments else {
+            return self.speak_text(body);
+        };
 
-            let code = unsafe { aitalked.close_kana(job_id, 0) };
-            if code != ResultCode::SUCCESS {
-                ctx.channel
-                    .send(Err(anyhow!("Failed to aitalked.close_kana {code:?}")))
-                    .unwrap();
+        let mut samples = vec![];
+        let mut cues = vec![];
 
-                continue;
+        for segment in segments {
+            match segment {
+                Segment::Break { ms } => {
+                    samples.resize(samples.len() + audio::ms_to_samples(*ms), 0);
+                }
+                Segment::Speech { text, prosody } => {
+                    let segment_body = Request {
+                        voice_id: prosody
+                            .voice_id
+                            .clone()
+                            .unwrap_or_else(|| body.voice_id.clone()),
+                        text: text.clone(),
+                        speed: body.speed * prosody.rate,
+                        pitch: body.pitch * prosody.pitch,
+                        volume: body.volume * prosody.volume,
+                        segments: None,
+                        ..body.clone()
+                    };
+
+                    let (pcm, segment_cues) = self.speak_text(&segment_body)?;
+                    let offset = samples.len() as u32;
+
+                    cues.extend(segment_cues.into_iter().map(|cue| Cue {
+                        position: cue.position + offset,
+                        ..cue
+                    }));
+
+                    samples.extend(pcm);
+                }
             }
+        }
 
-            // Avoiding aitalked.text_to_speech INVALID_ARGUMENT
-            if !kana.is_empty() {
-                // Add '\0'
-                kana.push(0);
+        Ok((samples, cues))
+    }
 
-                let kana = CStr::from_bytes_with_nul(&kana).unwrap();
+    /// Speaks the whole text of `body`, chunk by chunk if it is long, with
+    /// `pause_sentence` between chunks. Returns a cue at the start of every
+    /// chunk when there is more than one.
+    fn speak_text(&mut self, body: &Request) -> Result<(Vec<i16>, Vec<Cue>)> {
+        let chunks = text::chunk::split(&body.text, self.max_chunk_chars);
 
-                // unload
-                boxed_tts_param.tts_param_mut().proc_text_buf = None;
+        if chunks.len() <= 1 {
+            return Ok((self.synthesize(body)?, vec![]));
+        }
 
-                let t_kana_ready = Instant::now();
+        let gap = audio::ms_to_samples(body.pause_sentence.max(0) as u32);
 
-                /*\
-                |*| Start Kana2Speech
-                \*/
-                boxed_tts_param.tts_param_mut().proc_raw_buf = Some(raw_buf_callback);
-                boxed_tts_param.tts_param_mut().proc_event_tts = Some(tts_event_callback);
-                let code = unsafe { aitalked.set_param(boxed_tts_param.tts_param()) };
-                if code != ResultCode::SUCCESS {
-                    ctx.channel
-                        .send(Err(anyhow!(
-                            "Failed to aitalked.set_param (kana_to_speech / set) {code:?}"
-                        )))
-                        .unwrap();
+        let mut samples = vec![];
+        let mut cues = vec![];
 
-                    continue;
-                }
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            if i > 0 {
+                samples.resize(samples.len() + gap, 0);
+            }
 
-                let mut job_id = 0;
-                let (tx, mut rx) = mpsc::channel(1);
-
-                let mut buffer = vec![0; WAV_HEADER_SIZE];
-
-                let mut context = TextToSpeechContext {
-                    aitalked,
-                    buffer: &mut buffer,
-                    notify: tx.clone(),
-                    len_raw_buf_words: boxed_tts_param.tts_param().len_raw_buf_words,
-                };
-
-                let code = unsafe {
-                    aitalked.text_to_speech(
-                        &mut job_id,
-                        &mut context as *mut TextToSpeechContext as *mut std::ffi::c_void,
-                        kana,
-                    )
-                };
-                if code != ResultCode::SUCCESS {
-                    ctx.channel
-                        .send(Err(anyhow!("Failed to aitalked.text_to_speech {code:?}")))
-                        .unwrap();
-
-                    continue;
+            let chunk_body = Request {
+                text: chunk,
+                ..body.clone()
+            };
+
+            let key = serde_json::to_string(&(
+                &chunk_body.voice_id,
+                chunk_body.volume,
+                chunk_body.speed,
+                chunk_body.pitch,
+                chunk_body.range,
+                chunk_body.pause_middle,
+                chunk_body.pause_long,
+                chunk_body.pause_sentence,
+                chunk_body.ruby,
+                &chunk_body.text,
+            ))
+            .unwrap();
+
+            let pcm = match self.chunk_cache.get(&key) {
+                Some(pcm) => pcm,
+                None => {
+                    let pcm = Arc::new(self.synthesize(&chunk_body)?);
+                    let size = (pcm.len() * 2) as u64;
+                    self.chunk_cache.insert(key, pcm.clone(), size);
+                    pcm
                 }
+            };
 
-                rx.blocking_recv().unwrap();
+            cues.push(Cue {
+                position: samples.len() as u32,
+                label: chunk_body.text.trim().to_string(),
+            });
 
-                drop(context);
+            samples.extend_from_slice(&pcm);
+        }
 
-                let code = unsafe { aitalked.close_speech(job_id, 0) };
-                if code != ResultCode::SUCCESS {
-                    ctx.channel
-                        .send(Err(anyhow!("Failed to aitalked.close_speech {code:?}")))
-                        .unwrap();
+        Ok((samples, cues))
+    }
+}
 
-                    continue;
-                }
+/// Sizes and limits of a worker.
+#[derive(Debug, Clone, Copy)]
+pub struct EngineConfig {
+    pub kana_cache_bytes: u64,
+    pub chunk_cache_bytes: u64,
+    pub max_chunk_chars: usize,
+}
+
+pub fn event_loop(
+    aitalked: Aitalked,
+    boxed_tts_param: BoxedTtsParam,
+    scheduler: Arc<Scheduler>,
+    config: EngineConfig,
+    stats: Arc<Stats>,
+) {
+    let mut engine = Engine {
+        aitalked,
+        boxed_tts_param,
+        kana_cache: Tier::new(config.kana_cache_bytes),
+        chunk_cache: Tier::new(config.chunk_cache_bytes),
+        max_chunk_chars: config.max_chunk_chars,
+        dictionary_generation: crate::dictionary::generation(),
+    };
 
-                let t_speech_ready = Instant::now();
+    loop {
+        let ctx = scheduler.pop();
+
+        let t_start_at = Instant::now();
 
-                tracing::info!(
-                    "Voice: {}, Kana: {:?}, Speech: {:?}",
-                    voice_name,
-                    t_kana_ready - t_start_at,
-                    t_speech_ready - t_kana_ready,
-                );
+        if ctx.channel.is_closed() {
+            tracing::info!(
+                "Voice: {}, dropped as nobody waits for it",
+                ctx.body.voice_id
+            );
+            scheduler.finish();
+            continue;
+        }
 
-                let filesize = buffer.len();
-                let bodysize = buffer.len() - WAV_HEADER_SIZE;
-                let mut file = Cursor::new(buffer);
-                file.write_all(b"RIFF").unwrap();
-                file.write_all(&(filesize as u32).to_le_bytes()).unwrap();
-                file.write_all(b"WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00")
-                    .unwrap();
-                file.write_all(&44100u32.to_le_bytes()).unwrap();
-                file.write_all(&(44100u32 * 2).to_le_bytes()).unwrap();
-                file.write_all(b"\x02\x00\x10\x00data").unwrap();
-                file.write_all(&(bodysize as u32).to_le_bytes()).unwrap();
+        if ctx.deadline.is_some_and(|deadline| deadline < t_start_at) {
+            tracing::info!("Voice: {}, dropped after its deadline", ctx.body.voice_id);
+            scheduler.finish();
+            let _ = ctx.channel.send(Err(Expired.into()));
+            continue;
+        }
 
-                let buffer = file.into_inner();
+        engine.refresh_dictionaries();
 
-                ctx.channel.send(Ok(buffer)).unwrap();
+        let result = engine.speak(&ctx.body).and_then(|(mut samples, mut cues)| {
+            post_process(&ctx.body, &mut samples, &mut cues);
 
-                continue;
+            if let Some(limit) = &ctx.body.output_limit {
+                limit.enforce(&mut samples, &mut cues)?;
             }
-        }
 
-        // return empty buffer
-        let buffer = vec![0; WAV_HEADER_SIZE];
-        let mut file = Cursor::new(buffer);
+            Ok(to_wav(&ctx.body, samples, cues))
+        });
 
-        file.write_all(b"RIFF").unwrap();
-        file.write_all(&WAV_HEADER_SIZE.to_le_bytes()).unwrap();
-        file.write_all(b"WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00")
-            .unwrap();
-        file.write_all(&44100u32.to_le_bytes()).unwrap();
-        file.write_all(&(44100u32 * 2).to_le_bytes()).unwrap();
-        file.write_all(b"\x02\x00\x10\x00data").unwrap();
-        file.write_all(&0u32.to_le_bytes()).unwrap();
+        stats.record(t_start_at.elapsed());
+        scheduler.finish();
 
-        let buffer = file.into_inner();
-        ctx.channel.send(Ok(buffer)).unwrap();
+        // The requester may have given up in the meantime
+        let _ = ctx.channel.send(result);
     }
 }